@@ -0,0 +1,123 @@
+//! Queries behind inline answers and populating, on generated chats of 1k,
+//! 100k and 1M messages. Chats are generated from a fixed seed, so runs are
+//! comparable across changes.
+//!
+//! Run all of them with `cargo bench`, or some with a filter, e.g.
+//! `cargo bench -- search/100000`. Building the 1M message chat takes a while
+//! before anything is measured.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use realmkbot::db::{Filter, MessageRecord, Messages, SearchOrder, Serve};
+
+const SIZES: [i64; 3] = [1_000, 100_000, 1_000_000];
+
+/// Quotes are made of these, so searches find something at every size
+const WORDS: [&str; 16] = [
+    "the", "road", "goes", "ever", "on", "and", "down", "from", "door", "where", "it", "began",
+    "now", "far", "ahead", "has",
+];
+
+/// xorshift, the same numbers on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// A chat of `size` messages, with ids from 1 and every tenth one missing as
+/// if deleted
+fn chat(size: i64) -> Messages {
+    let db = Messages::open(":memory:").unwrap();
+    let mut rng = Rng(0x005e_ed0f_c4a7);
+
+    db.execute_batch("BEGIN").unwrap();
+    for id in (1..=size + size / 9).filter(|id| id % 10 != 0) {
+        let text = (0..3 + rng.below(10))
+            .map(|_| WORDS[rng.below(WORDS.len())])
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        db.insert_one(&MessageRecord {
+            id: id << 20,
+            in_chat_id: id,
+            text: Some(text),
+            is_forwarded: rng.below(2) == 0,
+            date: 1_600_000_000 + id * 60,
+            reactions: rng.below(20) as i64,
+            servable: true,
+            ..MessageRecord::default()
+        })
+        .unwrap();
+    }
+    db.execute_batch("COMMIT").unwrap();
+
+    db
+}
+
+fn filter() -> Filter {
+    Filter {
+        serve: Serve::All,
+        ..Filter::default()
+    }
+}
+
+fn bench(c: &mut Criterion) {
+    for size in SIZES {
+        let db = chat(size);
+        let filter = filter();
+
+        let mut group = c.benchmark_group("search");
+        for (name, order) in [
+            ("recent", SearchOrder::Recent),
+            ("relevance", SearchOrder::Relevance),
+            ("random", SearchOrder::Random),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, size), &size, |b, _| {
+                b.iter(|| db.search("door where", 10, &filter, order).unwrap())
+            });
+        }
+        group.finish();
+
+        let mut group = c.benchmark_group("random");
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            let mut seed = 0;
+            b.iter(|| {
+                seed += 1;
+                db.random_seeded(10, &filter, seed).unwrap()
+            })
+        });
+        group.finish();
+
+        // What populate looks up for every id before fetching it
+        let mut group = c.benchmark_group("existing_ids");
+        group.bench_with_input(BenchmarkId::new("exists", size), &size, |b, _| {
+            let mut rng = Rng(size as u64);
+            b.iter_batched(
+                || rng.below(size as usize) as i64 + 1,
+                |id| db.exists(id).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("gap_count", size), &size, |b, _| {
+            b.iter(|| db.gap_count(1, size).unwrap())
+        });
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    // The larger chats make single iterations slow
+    config = Criterion::default().sample_size(20);
+    targets = bench
+}
+criterion_main!(benches);
@@ -0,0 +1,291 @@
+//! `realmkbot db` subcommands, working on the database only without connecting
+//! to Telegram
+
+use std::io;
+
+use chrono::NaiveDate;
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+
+use crate::{
+    db::{ExportFormat, Filter, Messages, SearchOrder, SearchResult, Serve},
+    ChatLink, Config, SourceType, CHAT_LINK,
+};
+
+const USAGE: &str = "Usage: realmkbot db <command>, available commands: stats, purge, \
+                     prune-unservable, export, search, random, similar, fts";
+
+pub fn run(cmd: &str, mut args: impl Iterator<Item = String>) -> Result<()> {
+    if cmd != "db" {
+        bail!("Unknown command `{cmd}`. {USAGE}");
+    }
+    let Some(cmd) = args.next() else {
+        bail!("Expected a command. {USAGE}");
+    };
+
+    match cmd.as_str() {
+        "stats" => stats(),
+        "purge" => purge(),
+        "prune-unservable" => prune_unservable(),
+        "export" => export(args),
+        "search" => search(args),
+        "random" => random(args),
+        "similar" => similar(args),
+        "fts" => fts(args),
+        _ => bail!("Unknown command `{cmd}`. {USAGE}"),
+    }
+}
+
+fn open() -> Result<Messages> {
+    Messages::open(Config::load().db_path())
+}
+
+/// Open the database read-only, safe to use while the bot is running
+fn open_readonly() -> Result<Messages> {
+    Messages::open_readonly(Config::load().db_path())
+}
+
+/// Same filter as the bot, assuming a channel source unless configured
+/// otherwise. The id of the source chat isn't known offline, so self-forwards
+/// are treated as any forward.
+fn filter() -> Filter {
+    let config = Config::load();
+    config.filter(config.source_type.unwrap_or(SourceType::Channel), None)
+}
+
+/// Print database statistics
+fn stats() -> Result<()> {
+    let db = open()?;
+    let stats = db.stats()?;
+
+    println!("{stats}");
+    println!("Schema version: {}", db.schema_version()?);
+
+    Ok(())
+}
+
+/// Permanently remove soft-deleted messages
+fn purge() -> Result<()> {
+    let num = open()?.purge_deleted()?;
+
+    println!("{num} message(s) purged");
+
+    Ok(())
+}
+
+/// Drop messages with nothing to serve, keeping their ids
+fn prune_unservable() -> Result<()> {
+    let num = open()?.prune_unservable()?;
+
+    println!("{num} message(s) pruned");
+
+    Ok(())
+}
+
+/// Write messages to stdout.
+///
+/// `db export <csv|jsonl> [--forwarded] [--with-text] [--since <YYYY-MM-DD>]
+/// [--until <YYYY-MM-DD>] [--query <text>]`
+fn export(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let format = match args.next().as_deref() {
+        Some("csv") => ExportFormat::Csv,
+        Some("jsonl") => ExportFormat::JsonLines,
+        _ => bail!("Expected export format, `csv` or `jsonl`"),
+    };
+
+    let mut filter = Filter {
+        serve: Serve::All,
+        ..Filter::default()
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("Missing value for `{arg}`"))
+        };
+
+        match arg.as_str() {
+            "--forwarded" => filter.serve = Serve::Forwarded,
+            "--with-text" => filter.with_text = true,
+            "--since" => filter.since = Some(parse_date(&value()?)?),
+            "--until" => filter.until = Some(parse_date(&value()?)?),
+            "--query" => filter.query = Some(value()?),
+            _ => bail!("Unknown argument `{arg}`"),
+        }
+    }
+
+    let dates = Config::load().date_format();
+    let num = open()?.export_filtered(io::stdout().lock(), format, &filter, dates)?;
+
+    eprintln!("{num} message(s) exported");
+
+    Ok(())
+}
+
+/// Print what the bot would answer an inline query with.
+///
+/// `db search <query> [--order <random|recent|relevance|reactions>] [--limit <n>]`
+fn search(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut query = None;
+    let mut order = Config::load().search_order;
+    let mut limit = 10;
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("Missing value for `{arg}`"))
+        };
+
+        match arg.as_str() {
+            "--order" => {
+                order = match value()?.as_str() {
+                    "random" => SearchOrder::Random,
+                    "recent" => SearchOrder::Recent,
+                    "relevance" => SearchOrder::Relevance,
+                    "reactions" => SearchOrder::Reactions,
+                    other => bail!("Unknown order `{other}`"),
+                }
+            }
+            "--limit" => limit = parse_limit(&value()?)?,
+            _ if query.is_none() => query = Some(arg),
+            _ => bail!("Unknown argument `{arg}`"),
+        }
+    }
+
+    let Some(query) = query else {
+        bail!("Expected a query");
+    };
+
+    let db = open_readonly()?;
+    let link = chat_link(&db)?;
+    db.search(&query, limit, &filter(), order)?
+        .iter()
+        .for_each(|result| print_result(result, link));
+
+    Ok(())
+}
+
+/// Print random quotes the way the bot picks them for an empty query.
+///
+/// `db random [--limit <n>]`
+fn random(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut limit = 10;
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("Missing value for `{arg}`"))
+        };
+
+        match arg.as_str() {
+            "--limit" => limit = parse_limit(&value()?)?,
+            _ => bail!("Unknown argument `{arg}`"),
+        }
+    }
+
+    let db = open_readonly()?;
+    let link = chat_link(&db)?;
+    db.random(limit, &filter())?
+        .iter()
+        .for_each(|result| print_result(result, link));
+
+    Ok(())
+}
+
+/// Print groups of near-duplicate quotes, without changing anything.
+///
+/// `db similar [--threshold <0 to 1>]`, 0.8 by default
+fn similar(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let threshold = match args.next().as_deref() {
+        None => 0.8,
+        Some("--threshold") => match args.next().map(|x| x.parse::<f64>()) {
+            Some(Ok(threshold)) if (0.0..=1.0).contains(&threshold) => threshold,
+            _ => bail!("Expected a threshold from 0 to 1"),
+        },
+        Some(arg) => bail!("Unknown argument `{arg}`"),
+    };
+
+    let groups = open_readonly()?.find_similar(threshold)?;
+
+    for group in &groups {
+        for (in_chat_id, text) in group {
+            println!("#{in_chat_id} {text}");
+        }
+        println!();
+    }
+    println!("{} group(s) of similar quotes", groups.len());
+
+    Ok(())
+}
+
+/// Check the full-text index against the message table, or rebuild it.
+///
+/// `db fts <check|rebuild>`
+fn fts(mut args: impl Iterator<Item = String>) -> Result<()> {
+    match args.next().as_deref() {
+        Some("check") => {
+            let check = open_readonly()?.fts_check()?;
+
+            println!("Messages: {}", check.rows);
+            println!("Indexed:  {}", check.indexed);
+            println!(
+                "Integrity check: {}",
+                if check.consistent { "passed" } else { "failed" }
+            );
+
+            if !check.is_ok() {
+                warn!(
+                    "Full-text index is out of sync, run `realmkbot db fts rebuild` to repair it"
+                );
+            }
+        }
+        Some("rebuild") => {
+            open()?.fts_rebuild()?;
+            println!("Full-text index rebuilt");
+        }
+        _ => bail!("Expected `check` or `rebuild`"),
+    }
+
+    Ok(())
+}
+
+fn parse_limit(limit: &str) -> Result<u8> {
+    limit
+        .parse()
+        .map_err(|_| eyre!("Invalid limit `{limit}`, expected 0 to 255"))
+}
+
+/// How the bot last linked to messages of the source chat, nothing if it never
+/// resolved the chat
+fn chat_link(db: &Messages) -> Result<ChatLink> {
+    Ok(db
+        .setting(CHAT_LINK)?
+        .and_then(|link| serde_json::from_str(&link).ok())
+        .unwrap_or(ChatLink::None))
+}
+
+/// One result per line, prefixed with its in-chat id and followed by its
+/// permalink if it has one
+fn print_result(result: &SearchResult, link: ChatLink) {
+    let text = match (&result.text, result.media_kind) {
+        (Some(text), _) => text.replace('\n', " "),
+        (None, Some(kind)) => format!("[{}]", kind.as_str()),
+        (None, None) => String::new(),
+    };
+
+    let permalink = link
+        .permalink(&Config::load().chat_name, result.in_chat_id)
+        .unwrap_or_default();
+    println!("#{} {text} {permalink}", result.in_chat_id);
+}
+
+/// Parse a `YYYY-MM-DD` date into the unix timestamp of its midnight in the
+/// configured timezone
+fn parse_date(date: &str) -> Result<i64> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|day| Config::load().date_format().midnight(day))
+        .ok_or_else(|| eyre!("Invalid date `{date}`, expected YYYY-MM-DD"))
+}
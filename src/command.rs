@@ -0,0 +1,399 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_eyre::Result;
+use rust_tdlib::types::{GetUser, Message, MessageContent, MessageSender};
+use tap::Pipe;
+
+use crate::{logging, tdlib, App, Chat, RepopulateRequest, PAUSED};
+
+/// Commands that can be sent to the bot in private chat. All but `Help` and
+/// `Whoami` are for admins only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `/start` or `/help`, explaining how to use the bot
+    Help,
+    /// The sender's user id and whether they're an admin, to check `admins`
+    /// against
+    Whoami,
+    /// Configured admins, with their usernames
+    Admins,
+    /// Digest of the most sent quotes over a period, optionally posted to the
+    /// source chat
+    Summary { period: Period, post: bool },
+    /// Stop counting sends of a user
+    Ban(i64),
+    /// Count sends of a previously banned user again
+    Unban(i64),
+    /// Set the number of quotes a user sent back to 0
+    ResetUser(i64),
+    /// Populate in the background without restarting, optionally fetching
+    /// stored messages again
+    Repopulate { force: bool },
+    /// Most reported quotes
+    Reports,
+    /// Replace the log filter, in the same format as `RUST_LOG`
+    LogLevel(String),
+    /// Stop or start serving inline queries again, e.g. during maintenance
+    Pause(bool),
+    /// Flood waits slept out since the bot started
+    FloodWaits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    fn seconds(self) -> i64 {
+        match self {
+            Period::Day => 24 * 60 * 60,
+            Period::Week => 7 * 24 * 60 * 60,
+            Period::Month => 30 * 24 * 60 * 60,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+}
+
+const USAGE: &str = "Available commands:
+/help
+/summary [day|week|month] [post]
+/ban <user id>
+/unban <user id>
+/resetuser <user id>
+/repopulate [--force]
+/reports
+/loglevel <directives>
+/whoami
+/admins
+/pause
+/resume
+/floodwaits";
+
+impl Command {
+    /// Whether the command changes the database, which read-only instances
+    /// can't do
+    fn writes(&self) -> bool {
+        matches!(
+            self,
+            Command::Ban(_)
+                | Command::Unban(_)
+                | Command::ResetUser(_)
+                | Command::Repopulate { .. }
+        )
+    }
+
+    /// Parse a command out of a message, returning the text to reply with if it
+    /// isn't a valid one. `None` if the command is addressed to another bot.
+    pub fn parse(text: &str, username: &str) -> Option<Result<Self, String>> {
+        let mut args = text.split_whitespace();
+        let cmd = args.next()?.strip_prefix('/')?;
+
+        // Commands may be suffixed with a bot's username, e.g. `/summary@mkbot`
+        let name = match cmd.split_once('@') {
+            Some((name, to)) if to.eq_ignore_ascii_case(username) => name,
+            Some(_) => return None,
+            None => cmd,
+        };
+
+        Self::parse_args(name, args).pipe(Some)
+    }
+
+    fn parse_args<'a>(name: &str, mut args: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        match name {
+            "start" | "help" => Ok(Command::Help),
+            "whoami" => Ok(Command::Whoami),
+            "admins" => Ok(Command::Admins),
+            "summary" => {
+                let mut period = Period::Week;
+                let mut post = false;
+
+                for arg in args {
+                    match arg {
+                        "day" => period = Period::Day,
+                        "week" => period = Period::Week,
+                        "month" => period = Period::Month,
+                        "post" => post = true,
+                        _ => return Err(format!("Unknown argument `{arg}`\n\n{USAGE}")),
+                    }
+                }
+
+                Ok(Command::Summary { period, post })
+            }
+            "ban" => parse_user_id(args.next()).map(Command::Ban),
+            "unban" => parse_user_id(args.next()).map(Command::Unban),
+            "resetuser" => parse_user_id(args.next()).map(Command::ResetUser),
+            "reports" => Ok(Command::Reports),
+            "pause" => Ok(Command::Pause(true)),
+            "resume" => Ok(Command::Pause(false)),
+            "floodwaits" => Ok(Command::FloodWaits),
+            "loglevel" => match args.collect::<Vec<_>>().join(",") {
+                directives if directives.is_empty() => Err(format!(
+                    "Expected log directives, e.g. `realmkbot=debug`\n\n{USAGE}"
+                )),
+                directives => logging::check_filter(&directives)
+                    .map(|()| Command::LogLevel(directives))
+                    .map_err(|e| format!("{e}\n\n{USAGE}")),
+            },
+            "repopulate" => match args.next() {
+                None => Ok(Command::Repopulate { force: false }),
+                Some("--force") => Ok(Command::Repopulate { force: true }),
+                Some(arg) => Err(format!("Unknown argument `{arg}`\n\n{USAGE}")),
+            },
+            _ => Err(USAGE.to_owned()),
+        }
+    }
+}
+
+fn parse_user_id(arg: Option<&str>) -> Result<i64, String> {
+    arg.and_then(|id| id.parse().ok())
+        .ok_or_else(|| format!("Expected a user id\n\n{USAGE}"))
+}
+
+/// Whether the message was sent to the bot in a private chat
+pub fn is_private(msg: &Message) -> bool {
+    matches!(msg.sender_id(), MessageSender::User(user) if user.user_id() == msg.chat_id())
+}
+
+impl App<Chat> {
+    pub(crate) async fn handle_private_message(&self, msg: &Message) -> Result<()> {
+        let MessageSender::User(user) = msg.sender_id() else {
+            return Ok(());
+        };
+        let MessageContent::MessageText(text) = msg.content() else {
+            return Ok(());
+        };
+        let text = text.text().text();
+        let is_admin = self.config.admins.contains(&user.user_id());
+
+        // Anything that isn't a command gets the help text
+        let reply = match Command::parse(text, &self.username) {
+            None if text.starts_with('/') => return Ok(()),
+            None | Some(Ok(Command::Help)) => self.help(is_admin),
+            Some(Ok(Command::Whoami)) => format!(
+                "Your user id is {}, you're {}",
+                user.user_id(),
+                if is_admin { "an admin" } else { "not an admin" }
+            ),
+            Some(_) if !is_admin => {
+                debug!(
+                    "Command from non-admin {}, replying with help",
+                    user.user_id()
+                );
+                self.help(false)
+            }
+            Some(Ok(cmd)) if cmd.writes() && self.config.read_only => {
+                "This bot is read-only, send the command to the writer instead".to_owned()
+            }
+            Some(Ok(cmd)) => {
+                info!("Command from admin {}: {text}", user.user_id());
+                self.run_command(cmd, msg.chat_id()).await?
+            }
+            Some(Err(reply)) => reply,
+        };
+
+        self.send_text(msg.chat_id(), reply).await
+    }
+
+    /// Configured help text, followed by the commands for admins
+    fn help(&self, is_admin: bool) -> String {
+        let help = self.config.help_text.replace("{bot}", &self.username);
+
+        if is_admin {
+            format!("{help}\n\n{USAGE}")
+        } else {
+            help
+        }
+    }
+
+    async fn run_command(&self, cmd: Command, chat_id: i64) -> Result<String> {
+        match cmd {
+            Command::Help => Ok(self.help(true)),
+            Command::Summary { period, post } => {
+                let summary = self.summary(period)?;
+
+                if post {
+                    self.send_text(self.chat.id, &summary).await?;
+                }
+
+                Ok(summary)
+            }
+            Command::Ban(user_id) => {
+                self.db.set_blocked(user_id, true)?;
+                info!("Banned user {user_id}");
+
+                Ok(format!("User {user_id} banned"))
+            }
+            Command::Unban(user_id) => {
+                self.db.set_blocked(user_id, false)?;
+                info!("Unbanned user {user_id}");
+
+                if self.config.user_blocklist.contains(&user_id) {
+                    return Ok(format!(
+                        "User {user_id} unbanned, but is still blocked by `user_blocklist`"
+                    ));
+                }
+
+                Ok(format!("User {user_id} unbanned"))
+            }
+            Command::ResetUser(user_id) => match self.db.reset_user(user_id)? {
+                // Private chats share the id of the user
+                Some(count) => {
+                    info!("Count of user {user_id} reset from {count} by admin {chat_id}");
+                    Ok(format!(
+                        "User {user_id} reset, they had sent {count} quote(s)"
+                    ))
+                }
+                None => Ok(format!("User {user_id} hasn't sent anything")),
+            },
+            Command::Repopulate { force } => {
+                if self.populating.replace(true) {
+                    return Ok("Already populating".to_owned());
+                }
+                self.repopulate.set(Some(RepopulateRequest {
+                    chat_id: Some(chat_id),
+                    force,
+                }));
+                info!("Repopulate requested (force: {force})");
+
+                Ok("Repopulating, will report back when done".to_owned())
+            }
+            Command::Reports => self.reports(),
+            // Private chats share the id of the user
+            Command::Whoami => Ok(format!("Your user id is {chat_id}, you're an admin")),
+            Command::Admins => self.admins().await,
+            Command::Pause(paused) => self.pause(paused),
+            Command::FloodWaits => {
+                let (count, secs) = tdlib::flood_waits();
+                Ok(format!(
+                    "{count} flood wait(s) slept out for {secs}s in total since starting"
+                ))
+            }
+            Command::LogLevel(directives) => {
+                logging::set_filter(&directives);
+                info!("Log filter set to `{directives}`");
+
+                Ok(format!("Log filter set to `{directives}`"))
+            }
+        }
+    }
+
+    fn pause(&self, paused: bool) -> Result<String> {
+        if self.paused.replace(paused) == paused {
+            return Ok(if paused {
+                "Already paused"
+            } else {
+                "Not paused"
+            }
+            .to_owned());
+        }
+
+        // Replicas have a flag of their own
+        if self.config.persist_pause && !self.config.read_only {
+            self.db.set_setting(PAUSED, &paused.to_string())?;
+        }
+
+        if paused {
+            info!("Serving paused");
+            Ok("Paused, inline queries get `paused_text` until /resume".to_owned())
+        } else {
+            info!("Serving resumed");
+            Ok("Resumed".to_owned())
+        }
+    }
+
+    async fn admins(&self) -> Result<String> {
+        if self.config.admins.is_empty() {
+            return Ok("No admins configured".to_owned());
+        }
+
+        let mut list = "Admins:\n".to_owned();
+        for &user_id in &self.config.admins {
+            list += &format!("\n{user_id} {}", self.user_label(user_id).await);
+        }
+
+        Ok(list)
+    }
+
+    /// `@username` of a user, or their name if they have none. Looked up once
+    /// and cached, as admins rarely change their username.
+    async fn user_label(&self, user_id: i64) -> String {
+        if let Some(label) = self.user_labels.borrow().get(&user_id) {
+            return label.clone();
+        }
+
+        let user = GetUser::builder()
+            .user_id(user_id)
+            .build()
+            .pipe(|s| self.client.get_user(s))
+            .await;
+
+        let label = match user {
+            Ok(user) if !user.username().is_empty() => format!("@{}", user.username()),
+            Ok(user) => user.first_name().to_owned(),
+            // TDLib only knows users the bot has seen, don't cache so it's
+            // tried again once the user talks to the bot
+            Err(e) => {
+                debug!("Failed to get user {user_id}: {e:#}");
+                return "(unknown, hasn't talked to the bot yet)".to_owned();
+            }
+        };
+
+        self.user_labels.borrow_mut().insert(user_id, label.clone());
+
+        label
+    }
+
+    fn reports(&self) -> Result<String> {
+        let reported = self.db.most_reported(10)?;
+
+        if reported.is_empty() {
+            return Ok("No quotes were reported".to_owned());
+        }
+
+        let mut list = "Most reported quotes:\n".to_owned();
+        for (i, (quote, reports)) in reported.into_iter().enumerate() {
+            list += &format!(
+                "\n{}. #{} {} ({reports} reports)",
+                i + 1,
+                quote.in_chat_id,
+                quote.text.unwrap_or_default()
+            );
+        }
+
+        Ok(list)
+    }
+
+    fn summary(&self, period: Period) -> Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let top = self.db.top_sent(now - period.seconds(), 5)?;
+
+        if top.is_empty() {
+            return Ok(format!("No quotes were sent this {}", period.name()));
+        }
+
+        let since = self.config.date_format().day(now - period.seconds());
+        let mut summary = format!("Top quotes of the {} since {since}:\n", period.name());
+        for (i, (quote, sends)) in top.into_iter().enumerate() {
+            summary += &format!(
+                "\n{}. {} ({sends} sends)",
+                i + 1,
+                quote.text.unwrap_or_default()
+            );
+        }
+
+        Ok(summary)
+    }
+}
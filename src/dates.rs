@@ -0,0 +1,40 @@
+//! Dates shown to people, in the configured `timezone` and `locale`
+
+use chrono::{Locale, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DateFormat {
+    pub tz: Tz,
+    pub locale: Locale,
+}
+
+impl DateFormat {
+    /// Full timestamp with offset, still machine-readable, e.g.
+    /// `2023-05-06T21:00:00+09:00`
+    pub fn rfc3339(&self, ts: i64) -> String {
+        self.tz
+            .timestamp_opt(ts, 0)
+            .single()
+            .map(|date| date.to_rfc3339())
+            .unwrap_or_else(|| ts.to_string())
+    }
+
+    /// Day in the locale's preferred format, e.g. `05/06/23` or `06.05.2023`
+    pub fn day(&self, ts: i64) -> String {
+        self.tz
+            .timestamp_opt(ts, 0)
+            .single()
+            .map(|date| date.format_localized("%x", self.locale).to_string())
+            .unwrap_or_else(|| ts.to_string())
+    }
+
+    /// Unix timestamp of the midnight starting `day`, `None` if the day
+    /// starts later because of a DST change
+    pub fn midnight(&self, day: NaiveDate) -> Option<i64> {
+        self.tz
+            .from_local_datetime(&day.and_hms_opt(0, 0, 0)?)
+            .earliest()
+            .map(|date| date.timestamp())
+    }
+}
@@ -1,30 +1,133 @@
 use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt::{self, Display, Formatter},
+    hash::{Hash, Hasher},
+    io::{self, Write},
     ops::{Deref, DerefMut},
     path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use color_eyre::{eyre::Context, Result};
-use rusqlite::{params, Connection};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use rusqlite::{
+    functions::FunctionFlags,
+    params, params_from_iter,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    Connection, ErrorCode, OpenFlags, OptionalExtension, Row, Statement, ToSql,
+};
 use rusqlite_migration::{Migrations, M};
 use rust_tdlib::types::{
-    FormattedText, InputInlineQueryResult, InputInlineQueryResultArticle, InputMessageContent,
-    InputMessageText, Message, MessageContent,
+    File, FormattedText, InlineKeyboardButton, InlineKeyboardButtonType,
+    InlineKeyboardButtonTypeCallback, InlineKeyboardButtonTypeUrl, InputFile, InputFileRemote,
+    InputInlineQueryResult, InputInlineQueryResultAnimation, InputInlineQueryResultArticle,
+    InputInlineQueryResultPhoto, InputInlineQueryResultSticker, InputMessageAnimation,
+    InputMessageContent, InputMessagePhoto, InputMessageSticker, InputMessageText, Message,
+    MessageContent, MessageForwardOrigin, MessageSender, ReplyMarkup, ReplyMarkupInlineKeyboard,
+    ThumbnailFormat,
 };
 use serde::{Deserialize, Serialize};
-use tap::Pipe;
+use tap::{Pipe, Tap};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{dates::DateFormat, plural::Forms};
 
 #[derive(Debug)]
 pub struct Messages(Connection);
 
+/// Chat id counts of sent quotes are kept under when they aren't per chat,
+/// see `per_chat_stats`
+pub const UNSCOPED_CHAT: i64 = 0;
+
+/// Sends of each unblocked user who sent anything, from the chat bound as
+/// `?2` or from all of them if it's `NULL`
+const COUNTS: &str = "counts AS (SELECT user_id, SUM(count) AS count FROM user_count WHERE (?2 IS \
+                      NULL OR chat_id = ?2) AND user_id NOT IN (SELECT user_id FROM user WHERE \
+                      blocked) GROUP BY user_id HAVING SUM(count) > 0)";
+
 impl Messages {
     #[inline]
     pub fn open(p: impl AsRef<Path>) -> Result<Self> {
         Connection::open(p)?.pipe(Self).pre_start()?.pipe(Ok)
     }
 
-    #[inline]
-    fn pre_start(mut self) -> Result<Self> {
-        let migrations = Migrations::new(vec![
+    /// SQL functions used by queries, which have to be registered on every
+    /// connection
+    fn register_functions(&self) -> Result<()> {
+        self.create_scalar_function(
+            "word_match",
+            2,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| {
+                let text = ctx.get::<Option<String>>(0)?;
+                let words = ctx.get::<String>(1)?;
+                Ok(text.map_or(false, |text| contains_words(&text, &words)))
+            },
+        )?;
+        self.create_scalar_function(
+            "normalize",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| Ok(ctx.get::<Option<String>>(0)?.map(|text| normalize(&text))),
+        )?;
+        self.create_scalar_function(
+            "random_key",
+            3,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| Ok(random_key(ctx.get(0)?, ctx.get(1)?, ctx.get(2)?)),
+        )?;
+
+        Ok(())
+    }
+
+    /// Open an existing database without migrating it or writing to it. Its
+    /// schema must be the one this build migrates to.
+    pub fn open_readonly(p: impl AsRef<Path>) -> Result<Self> {
+        let db = Connection::open_with_flags(
+            p,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?
+        .pipe(Self);
+        db.register_functions()?;
+
+        let version = db.schema_version()?;
+        let expected = Self::latest_schema_version();
+        match version.cmp(&expected) {
+            Ordering::Greater => bail!(
+                "Database schema version {version} is newer than {expected}, which this build \
+                 supports. Upgrade realmkbot."
+            ),
+            Ordering::Less => bail!(
+                "Database schema version {version} is older than {expected}. Run the writer \
+                 once to migrate it."
+            ),
+            Ordering::Equal => {}
+        }
+
+        Ok(db)
+    }
+
+    /// Version of the schema this build migrates to
+    pub fn latest_schema_version() -> usize {
+        Self::migrations().len()
+    }
+
+    /// Version of the schema on disk, the number of migrations applied
+    pub fn schema_version(&self) -> Result<usize> {
+        // Migrations keep the version in `user_version`
+        self.pragma_query_value(None, "user_version", |row| row.get(0))
+            .wrap_err("Failed to get schema version")
+    }
+
+    /// Schema migrations, in order. The schema version is the number of them.
+    fn migrations() -> Vec<M<'static>> {
+        vec![
             M::up(
                 "CREATE TABLE message  (
                 id           INTEGER PRIMARY KEY,
@@ -35,7 +138,218 @@ impl Messages {
             )",
             ),
             M::up("CREATE UNIQUE INDEX message_in_chat_id ON message (in_chat_id)"),
-        ]);
+            M::up(
+                "ALTER TABLE message ADD COLUMN author_id INTEGER;
+                ALTER TABLE message ADD COLUMN author_name TEXT;",
+            ),
+            M::up(
+                "CREATE VIRTUAL TABLE message_fts USING fts5(
+                    text, content='message', content_rowid='id', tokenize='trigram'
+                );
+                CREATE TRIGGER message_fts_insert AFTER INSERT ON message BEGIN
+                    INSERT INTO message_fts (rowid, text) VALUES (new.id, new.text);
+                END;
+                CREATE TRIGGER message_fts_delete AFTER DELETE ON message BEGIN
+                    INSERT INTO message_fts (message_fts, rowid, text)
+                        VALUES ('delete', old.id, old.text);
+                END;
+                CREATE TRIGGER message_fts_update AFTER UPDATE ON message BEGIN
+                    INSERT INTO message_fts (message_fts, rowid, text)
+                        VALUES ('delete', old.id, old.text);
+                    INSERT INTO message_fts (rowid, text) VALUES (new.id, new.text);
+                END;
+                INSERT INTO message_fts (message_fts) VALUES ('rebuild');",
+            ),
+            M::up(
+                r#"ALTER TABLE message ADD COLUMN media_kind TEXT;
+                UPDATE message SET media_kind = CASE json_extract(CAST(raw AS TEXT), '$.content."@type"')
+                    WHEN 'messagePhoto' THEN 'photo'
+                    WHEN 'messageVideo' THEN 'video'
+                    WHEN 'messageAnimation' THEN 'animation'
+                    WHEN 'messageDocument' THEN 'document'
+                    WHEN 'messageAudio' THEN 'audio'
+                    WHEN 'messageVoiceNote' THEN 'voice_note'
+                    WHEN 'messageSticker' THEN 'sticker'
+                    WHEN 'messageVideoNote' THEN 'video_note'
+                END;
+                UPDATE message SET text = json_extract(CAST(raw AS TEXT), '$.content.caption.text')
+                    WHERE text IS NULL AND json_extract(CAST(raw AS TEXT), '$.content.caption.text') != '';"#,
+            ),
+            M::up("ALTER TABLE message ADD COLUMN deleted_at INTEGER"),
+            M::up(
+                "CREATE TABLE send (
+                    id          INTEGER PRIMARY KEY,
+                    user_id     INTEGER NOT NULL,
+                    in_chat_id  INTEGER NOT NULL,
+                    sent_at     INTEGER NOT NULL
+                );
+                CREATE INDEX send_sent_at ON send (sent_at);",
+            ),
+            M::up(
+                "CREATE TABLE user (
+                    user_id INTEGER PRIMARY KEY,
+                    count   INTEGER NOT NULL DEFAULT 0,
+                    blocked BOOLEAN NOT NULL DEFAULT FALSE
+                )",
+            ),
+            M::up(
+                "ALTER TABLE message ADD COLUMN date INTEGER;
+                UPDATE message SET date = json_extract(CAST(raw AS TEXT), '$.date');",
+            ),
+            M::up(
+                "ALTER TABLE message ADD COLUMN reply_to INTEGER;
+                UPDATE message SET reply_to = json_extract(CAST(raw AS TEXT), '$.reply_to_message_id')
+                    WHERE json_extract(CAST(raw AS TEXT), '$.reply_to_message_id') != 0
+                    AND json_extract(CAST(raw AS TEXT), '$.reply_in_chat_id')
+                        = json_extract(CAST(raw AS TEXT), '$.chat_id');",
+            ),
+            M::up("CREATE INDEX send_user ON send (user_id, in_chat_id, sent_at)"),
+            M::up(
+                r#"ALTER TABLE message ADD COLUMN thumbnail TEXT;
+                UPDATE message SET thumbnail = CASE media_kind
+                    WHEN 'photo' THEN json_extract(CAST(raw AS TEXT), '$.content.photo.sizes[0].photo.remote.id')
+                    WHEN 'sticker' THEN json_extract(CAST(raw AS TEXT), '$.content.sticker.thumbnail.file.remote.id')
+                    WHEN 'animation' THEN json_extract(CAST(raw AS TEXT), '$.content.animation.thumbnail.file.remote.id')
+                END;
+                UPDATE message SET thumbnail = NULL WHERE thumbnail = '';"#,
+            ),
+            M::up(
+                "ALTER TABLE message ADD COLUMN reactions INTEGER NOT NULL DEFAULT 0;
+                UPDATE message SET reactions = COALESCE((
+                    SELECT SUM(json_extract(value, '$.total_count'))
+                    FROM json_each(CAST(raw AS TEXT), '$.interaction_info.reactions')
+                ), 0);",
+            ),
+            M::up(
+                "CREATE TABLE report (
+                    in_chat_id  INTEGER NOT NULL,
+                    user_id     INTEGER NOT NULL,
+                    reported_at INTEGER NOT NULL,
+                    PRIMARY KEY (in_chat_id, user_id)
+                )",
+            ),
+            M::up(
+                "ALTER TABLE message ADD COLUMN servable BOOLEAN NOT NULL DEFAULT TRUE;
+                UPDATE message SET servable = text IS NOT NULL OR media_kind IS NOT NULL;",
+            ),
+            M::up(
+                r#"ALTER TABLE message ADD COLUMN file_id TEXT;
+                UPDATE message SET file_id = CASE media_kind
+                    WHEN 'photo' THEN json_extract(CAST(raw AS TEXT), '$.content.photo.sizes[#-1].photo.remote.id')
+                    WHEN 'sticker' THEN json_extract(CAST(raw AS TEXT), '$.content.sticker.sticker.remote.id')
+                    WHEN 'animation' THEN json_extract(CAST(raw AS TEXT), '$.content.animation.animation.remote.id')
+                END;
+                UPDATE message SET file_id = NULL WHERE file_id = '';"#,
+            ),
+            // Index the normalized text instead of the original
+            M::up(
+                "DROP TRIGGER message_fts_insert;
+                DROP TRIGGER message_fts_delete;
+                DROP TRIGGER message_fts_update;
+                DROP TABLE message_fts;
+                ALTER TABLE message ADD COLUMN search_text TEXT;
+                UPDATE message SET search_text = normalize(text);
+                CREATE VIRTUAL TABLE message_fts USING fts5(
+                    search_text, content='message', content_rowid='id', tokenize='trigram'
+                );
+                CREATE TRIGGER message_fts_insert AFTER INSERT ON message BEGIN
+                    INSERT INTO message_fts (rowid, search_text) VALUES (new.id, new.search_text);
+                END;
+                CREATE TRIGGER message_fts_delete AFTER DELETE ON message BEGIN
+                    INSERT INTO message_fts (message_fts, rowid, search_text)
+                        VALUES ('delete', old.id, old.search_text);
+                END;
+                CREATE TRIGGER message_fts_update AFTER UPDATE ON message BEGIN
+                    INSERT INTO message_fts (message_fts, rowid, search_text)
+                        VALUES ('delete', old.id, old.search_text);
+                    INSERT INTO message_fts (rowid, search_text) VALUES (new.id, new.search_text);
+                END;
+                INSERT INTO message_fts (message_fts) VALUES ('rebuild');",
+            ),
+            // Bookkeeping updates shouldn't touch the full-text index
+            M::up(
+                "ALTER TABLE message ADD COLUMN last_served_at INTEGER;
+                CREATE INDEX message_last_served_at ON message (last_served_at);
+                DROP TRIGGER message_fts_update;
+                CREATE TRIGGER message_fts_update AFTER UPDATE OF search_text ON message BEGIN
+                    INSERT INTO message_fts (message_fts, rowid, search_text)
+                        VALUES ('delete', old.id, old.search_text);
+                    INSERT INTO message_fts (rowid, search_text) VALUES (new.id, new.search_text);
+                END;",
+            ),
+            M::up(
+                "CREATE TABLE rating (
+                    in_chat_id INTEGER NOT NULL,
+                    user_id    INTEGER NOT NULL,
+                    score      INTEGER NOT NULL,
+                    PRIMARY KEY (in_chat_id, user_id)
+                );
+                ALTER TABLE message ADD COLUMN rating INTEGER NOT NULL DEFAULT 0;",
+            ),
+            M::up(
+                "CREATE TABLE setting (
+                    key   TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                )",
+            ),
+            // Names of forwards from users and chats are only known after
+            // asking TDLib, populating fills them in once origins are stored
+            // too, see `Populator::name_origins`
+            M::up(
+                r#"ALTER TABLE message ADD COLUMN forward_name TEXT;
+                UPDATE message SET forward_name = json_extract(CAST(raw AS TEXT), '$.forward_info.origin.sender_name')
+                    WHERE is_forwarded;
+                UPDATE message SET forward_name = NULL WHERE forward_name = '';"#,
+            ),
+            M::up("CREATE TABLE evicted (in_chat_id INTEGER PRIMARY KEY)"),
+            // Origins are filtered when serving rather than when storing, so
+            // whether a message is servable only depends on its content again
+            M::up(
+                r#"ALTER TABLE message ADD COLUMN forward_origin INTEGER;
+                UPDATE message SET forward_origin = CASE json_extract(CAST(raw AS TEXT), '$.forward_info.origin."@type"')
+                    WHEN 'messageForwardOriginUser' THEN json_extract(CAST(raw AS TEXT), '$.forward_info.origin.sender_user_id')
+                    WHEN 'messageForwardOriginChat' THEN json_extract(CAST(raw AS TEXT), '$.forward_info.origin.sender_chat_id')
+                    WHEN 'messageForwardOriginChannel' THEN json_extract(CAST(raw AS TEXT), '$.forward_info.origin.chat_id')
+                END
+                    WHERE is_forwarded;
+                UPDATE message SET servable = text IS NOT NULL OR media_kind IS NOT NULL
+                    WHERE deleted_at IS NULL;"#,
+            ),
+            // Users who sent quotes already know their way around
+            M::up(
+                "ALTER TABLE user ADD COLUMN welcomed BOOLEAN NOT NULL DEFAULT FALSE;
+                UPDATE user SET welcomed = TRUE WHERE count > 0;",
+            ),
+            // Counts are kept per source chat, those from before under
+            // `UNSCOPED_CHAT` until `Messages::scope_counts` claims them
+            M::up(
+                "CREATE TABLE user_count (
+                    user_id INTEGER NOT NULL,
+                    chat_id INTEGER NOT NULL,
+                    count   INTEGER NOT NULL DEFAULT 0,
+                    PRIMARY KEY (user_id, chat_id)
+                );
+                INSERT INTO user_count (user_id, chat_id, count)
+                    SELECT user_id, 0, count FROM user WHERE count > 0;
+                ALTER TABLE user DROP COLUMN count;",
+            ),
+        ]
+    }
+
+    #[inline]
+    fn pre_start(mut self) -> Result<Self> {
+        self.register_functions()?;
+
+        let migrations = Migrations::new(Self::migrations());
+
+        // Migrating would fail, point out why
+        let (version, latest) = (self.schema_version()?, Self::latest_schema_version());
+        if version > latest {
+            warn!(
+                "Database schema version {version} is ahead of {latest}, which this build \
+                 supports. Was realmkbot downgraded?"
+            );
+        }
 
         self.pragma_update(None, "journal_mode", "WAL")?;
         migrations.to_latest(&mut self)?;
@@ -43,55 +357,203 @@ impl Messages {
         Ok(self)
     }
 
-    pub fn random(&self, limit: u8) -> Result<Vec<SearchResult>> {
-        self.prepare(
-            "SELECT in_chat_id, text FROM message WHERE is_forwarded = TRUE AND text IS NOT NULL \
-             ORDER BY RANDOM() LIMIT ?",
-        )?
-        .query_map([limit], |row| {
-            SearchResult {
-                in_chat_id: row.get(0)?,
-                text: row.get(1)?,
-            }
-            .pipe(Ok)
-        })
+    pub fn random(&self, limit: u8, filter: &Filter) -> Result<Vec<SearchResult>> {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or_default();
+
+        self.random_seeded(limit, filter, seed)
+    }
+
+    /// Same as [`Messages::random`], but the same seed over the same rows always
+    /// yields the same results.
+    pub fn random_seeded(
+        &self,
+        limit: u8,
+        filter: &Filter,
+        seed: i64,
+    ) -> Result<Vec<SearchResult>> {
+        // SQLite's `RANDOM()` cannot be seeded, so rows are ordered by a hash of
+        // their in-chat id keyed by the seed instead, see `random_key`. Well
+        // rated quotes are up to 11 times as likely to come first, bad ones down
+        // to a 11th, and every 10 reactions add as much again up to 100.
+        const WEIGHT: &str = "(CASE WHEN message.rating >= 0 THEN 1.0 + MIN(message.rating, 10) \
+                              ELSE 1.0 / (1 - MAX(message.rating, -10)) END) \
+                              * (1.0 + MIN(message.reactions, 100) / 10.0)";
+
+        let condition = filter.condition();
+
+        self.prepare(&format!(
+            "SELECT {} FROM message {} WHERE {} AND {} ORDER BY message.in_chat_id IN ({}), \
+             random_key(:seed, message.in_chat_id, {WEIGHT}), message.in_chat_id LIMIT :limit",
+            SearchResult::COLUMNS,
+            SearchResult::JOINS,
+            condition.sql,
+            filter.servable(),
+            filter
+                .recent
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))?
+        .query_map(
+            condition
+                .params(&[(":limit", &limit), (":seed", &seed)])
+                .as_slice(),
+            SearchResult::from_row,
+        )
         .wrap_err("Failed to random")?
         .collect::<rusqlite::Result<Vec<SearchResult>>>()
         .wrap_err("Failed to collect search result")
     }
 
-    pub fn search(&self, reg: &str, limit: u8) -> Result<Vec<SearchResult>> {
-        self.prepare(
-            "SELECT in_chat_id, text FROM message WHERE text IS NOT NULL AND text LIKE ?1 AND \
-             is_forwarded = TRUE LIMIT ?2",
-        )?
-        .query_map(params![format!("%{reg}%"), limit], |row| {
-            SearchResult {
-                in_chat_id: row.get(0)?,
-                text: row.get(1)?,
-            }
-            .pipe(Ok)
-        })
-        .wrap_err("Failed to search")?
+    /// Quotes by authors whose name contains `name`, be it who forwarded
+    /// messages came from or who posted in group sources. Most reacted first.
+    pub fn by_author(&self, name: &str, limit: u8, filter: &Filter) -> Result<Vec<SearchResult>> {
+        let condition = filter.condition();
+
+        self.prepare(&format!(
+            "SELECT {} FROM message {} WHERE instr(normalize(coalesce(message.forward_name, \
+             message.author_name)), normalize(:name)) > 0 AND {} AND {} ORDER BY \
+             message.reactions DESC, message.in_chat_id DESC LIMIT :limit",
+            SearchResult::COLUMNS,
+            SearchResult::JOINS,
+            condition.sql,
+            filter.servable(),
+        ))?
+        .query_map(
+            condition
+                .params(&[(":name", &name), (":limit", &limit)])
+                .as_slice(),
+            SearchResult::from_row,
+        )
+        .wrap_err("Failed to search by author")?
+        .collect::<rusqlite::Result<Vec<SearchResult>>>()
+        .wrap_err("Failed to collect search result")
+    }
+
+    /// Newest quotes first
+    pub fn recent(&self, limit: u8, filter: &Filter) -> Result<Vec<SearchResult>> {
+        let condition = filter.condition();
+
+        self.prepare(&format!(
+            "SELECT {} FROM message {} WHERE {} AND {} ORDER BY message.in_chat_id DESC LIMIT \
+             :limit",
+            SearchResult::COLUMNS,
+            SearchResult::JOINS,
+            condition.sql,
+            filter.servable(),
+        ))?
+        .query_map(
+            condition.params(&[(":limit", &limit)]).as_slice(),
+            SearchResult::from_row,
+        )
+        .wrap_err("Failed to get recent quotes")?
         .collect::<rusqlite::Result<Vec<SearchResult>>>()
         .wrap_err("Failed to collect search result")
     }
 
+    pub fn search(
+        &self,
+        reg: &str,
+        limit: u8,
+        filter: &Filter,
+        order: SearchOrder,
+    ) -> Result<Vec<SearchResult>> {
+        self.search_iter(reg, limit.into(), filter, order)?
+            .iter()?
+            .collect()
+    }
+
+    /// Like [`Messages::search`], but prepares the search for its results to
+    /// be read one at a time through [`SearchRows::iter`] instead of collected,
+    /// for limits too large to hold in memory at once
+    pub fn search_iter(
+        &self,
+        reg: &str,
+        limit: u32,
+        filter: &Filter,
+        order: SearchOrder,
+    ) -> Result<SearchRows<'_>> {
+        let reg = &normalize(reg);
+        let condition = filter.search_condition(reg);
+
+        // The trigram index cannot match anything shorter than 3 characters
+        let (sql, pattern) = if order == SearchOrder::Relevance && reg.chars().count() >= 3 {
+            let sql = format!(
+                "SELECT {} FROM message_fts JOIN message ON message.id = message_fts.rowid {} \
+                 WHERE message_fts MATCH :pattern AND {} ORDER BY message_fts.rank LIMIT :limit",
+                SearchResult::COLUMNS,
+                SearchResult::JOINS,
+                condition.sql
+            );
+
+            (sql, format!("\"{}\"", reg.replace('"', "\"\"")))
+        } else {
+            let order_by = match order {
+                SearchOrder::Random => "RANDOM()",
+                SearchOrder::Recent | SearchOrder::Relevance => "message.in_chat_id DESC",
+                SearchOrder::Reactions => "message.reactions DESC, message.in_chat_id DESC",
+            };
+            let sql = format!(
+                "SELECT {} FROM message {} WHERE message.text IS NOT NULL AND \
+                 message.search_text LIKE :pattern ESCAPE '\\' AND {} ORDER BY {order_by} LIMIT \
+                 :limit",
+                SearchResult::COLUMNS,
+                SearchResult::JOINS,
+                condition.sql
+            );
+
+            // Wildcards typed by the user are searched for as they are
+            let escaped = reg
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            (sql, format!("%{escaped}%"))
+        };
+
+        Ok(SearchRows {
+            stmt: self.prepare(&sql)?,
+            condition,
+            pattern,
+            limit,
+        })
+    }
+
     pub fn insert_one(&self, msg: &MessageRecord) -> Result<()> {
         self.execute(
-            r"INSERT OR REPLACE INTO message (id, in_chat_id, text, is_forwarded, raw) VALUES (?1, ?2, ?3, ?4, ?5)",
-            (
+            // Only what comes from the message itself is overwritten, so ratings,
+            // deletions and serving history survive edits and repopulates
+            r"INSERT INTO message (id, in_chat_id, text, is_forwarded, raw, author_id, author_name, media_kind, date, reply_to, thumbnail, reactions, servable, file_id, search_text, forward_name, forward_origin) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+            ON CONFLICT (id) DO UPDATE SET in_chat_id = excluded.in_chat_id, text = excluded.text, is_forwarded = excluded.is_forwarded, raw = excluded.raw, author_id = excluded.author_id, author_name = excluded.author_name, media_kind = excluded.media_kind, date = excluded.date, reply_to = excluded.reply_to, thumbnail = excluded.thumbnail, reactions = excluded.reactions, servable = excluded.servable, file_id = excluded.file_id, search_text = excluded.search_text, forward_name = excluded.forward_name, forward_origin = excluded.forward_origin",
+            params![
                 &msg.id,
                 &msg.in_chat_id,
                 &msg.text,
                 &msg.is_forwarded,
                 &msg.raw,
-            ),
+                &msg.author_id,
+                &msg.author_name,
+                &msg.media_kind,
+                &msg.date,
+                &msg.reply_to,
+                &msg.thumbnail,
+                &msg.reactions,
+                &msg.servable,
+                &msg.file_id,
+                &msg.text.as_deref().map(normalize),
+                &msg.forward_name,
+                &msg.forward_origin,
+            ],
         )
         .wrap_err("Failed to insert message")
         .map(|_| ())
     }
 
+    /// Mark messages as deleted. They are no longer served but stay in the
+    /// database until purged.
     pub fn delete(&self, ids: &[i64]) -> Result<usize> {
         if ids.is_empty() {
             return Ok(0);
@@ -99,6 +561,25 @@ impl Messages {
 
         info!("Deleting {ids:?}");
 
+        let mut num = 0;
+        for id in ids {
+            num += self.execute(
+                "UPDATE message SET deleted_at = unixepoch() WHERE id = ?1 AND deleted_at IS NULL",
+                (id,),
+            )?;
+        }
+
+        Ok(num)
+    }
+
+    /// Permanently remove messages
+    pub fn purge(&self, ids: &[i64]) -> Result<usize> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        info!("Purging {ids:?}");
+
         let mut num = 0;
         for id in ids {
             num += self.execute("DELETE FROM message WHERE id = ?1", (id,))?;
@@ -107,82 +588,2803 @@ impl Messages {
         Ok(num)
     }
 
-    pub fn exists(&self, in_chat_id: i64) -> Result<bool> {
+    /// Permanently remove all messages previously marked as deleted, except
+    /// tombstones left by [`Messages::prune_unservable`]
+    pub fn purge_deleted(&self) -> Result<usize> {
+        self.execute(
+            "DELETE FROM message WHERE deleted_at IS NOT NULL AND servable",
+            [],
+        )
+        .wrap_err("Failed to purge deleted messages")
+    }
+
+    /// Mark messages with neither text nor media, e.g. service messages, which
+    /// can never be served, as deleted and drop their raw message. The rows
+    /// stay as tombstones, so populating doesn't fetch them again.
+    pub fn prune_unservable(&self) -> Result<usize> {
+        self.execute(
+            "UPDATE message SET deleted_at = unixepoch(), raw = X'' WHERE NOT servable AND \
+             deleted_at IS NULL",
+            [],
+        )
+        .wrap_err("Failed to prune unservable messages")
+    }
+
+    /// Groups of quotes whose texts are at least `threshold` (0 to 1) alike,
+    /// by trigram similarity ignoring case, spaces and punctuation. Groups are
+    /// transitive, so two quotes in one group may differ more than that.
+    pub fn find_similar(&self, threshold: f64) -> Result<Vec<Vec<(i64, String)>>> {
+        let quotes = self
+            .prepare(
+                "SELECT in_chat_id, text FROM message WHERE text IS NOT NULL AND deleted_at IS \
+                 NULL ORDER BY in_chat_id",
+            )?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })
+            .wrap_err("Failed to read quotes")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .wrap_err("Failed to collect quotes")?;
+
+        let trigrams = quotes
+            .iter()
+            .map(|(_, text)| trigrams(text))
+            .collect::<Vec<_>>();
+
+        // Only quotes sharing a trigram can be alike
+        let mut index = HashMap::<&[char; 3], Vec<usize>>::new();
+        for (i, set) in trigrams.iter().enumerate() {
+            for trigram in set {
+                index.entry(trigram).or_default().push(i);
+            }
+        }
+        // Trigrams of common words are in most quotes and would pair up about
+        // everything, taking quadratic time. Only rarer ones pick candidates,
+        // which are still compared over all their trigrams.
+        index.retain(|_, quotes| quotes.len() <= MAX_TRIGRAM_QUOTES);
+
+        // Union-find over quote indices, see `root`
+        let mut parent = (0..quotes.len()).collect::<Vec<_>>();
+
+        for (i, set) in trigrams.iter().enumerate() {
+            let candidates = set
+                .iter()
+                .filter_map(|trigram| index.get(trigram))
+                .flatten()
+                .copied()
+                .filter(|&j| j > i)
+                .collect::<HashSet<_>>();
+
+            for j in candidates {
+                let shared = set.intersection(&trigrams[j]).count();
+                let union = set.len() + trigrams[j].len() - shared;
+                if shared as f64 / union as f64 >= threshold {
+                    let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                    parent[a] = b;
+                }
+            }
+        }
+
+        let mut groups = HashMap::<usize, Vec<(i64, String)>>::new();
+        for (i, quote) in quotes.into_iter().enumerate() {
+            groups.entry(root(&mut parent, i)).or_default().push(quote);
+        }
+
+        groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .collect::<Vec<_>>()
+            .tap_mut(|groups| groups.sort_by_key(|group| group[0].0))
+            .pipe(Ok)
+    }
+
+    /// Compare the full-text index against the message table
+    pub fn fts_check(&self) -> Result<FtsCheck> {
+        let rows = self
+            .query_row("SELECT COUNT(*) FROM message", [], |row| row.get(0))
+            .wrap_err("Failed to count messages")?;
+        // Every message gets a row here when indexed, with text or without
+        let indexed = self
+            .query_row("SELECT COUNT(*) FROM message_fts_docsize", [], |row| {
+                row.get(0)
+            })
+            .wrap_err("Failed to count indexed messages")?;
+
+        let consistent = match self.execute(
+            "INSERT INTO message_fts (message_fts) VALUES ('integrity-check')",
+            [],
+        ) {
+            Ok(_) => true,
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::DatabaseCorrupt => {
+                false
+            }
+            Err(e) => return Err(e).wrap_err("Failed to check full-text index"),
+        };
+
+        Ok(FtsCheck {
+            rows,
+            indexed,
+            consistent,
+        })
+    }
+
+    /// Rebuild the full-text index from the message table
+    pub fn fts_rebuild(&self) -> Result<()> {
+        self.execute(
+            "INSERT INTO message_fts (message_fts) VALUES ('rebuild')",
+            [],
+        )
+        .wrap_err("Failed to rebuild full-text index")
+        .map(|_| ())
+    }
+
+    /// TDLib ids of up to `limit` messages whose reactions are worth checking
+    /// again: the newest ones, which are still being reacted to, and the most
+    /// reacted ones, which weigh the most
+    pub fn reaction_sample(&self, limit: usize) -> Result<Vec<i64>> {
+        self.prepare(
+            "SELECT id FROM (SELECT id FROM message WHERE deleted_at IS NULL ORDER BY date DESC \
+             LIMIT ?1) UNION SELECT id FROM (SELECT id FROM message WHERE deleted_at IS NULL \
+             ORDER BY reactions DESC LIMIT ?2)",
+        )?
+        .query_map(params![limit - limit / 2, limit / 2], |row| row.get(0))
+        .wrap_err("Failed to get reaction sample")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .wrap_err("Failed to collect reaction sample")
+    }
+
+    /// Permanently remove messages sent before `before`, a unix timestamp, and
+    /// hand the freed pages back to the WAL. Messages without a date are kept.
+    /// Their ids are remembered like evicted ones so populating doesn't fetch
+    /// them again.
+    pub fn prune_before(&self, before: i64) -> Result<usize> {
+        let tx = self
+            .unchecked_transaction()
+            .wrap_err("Failed to start transaction")?;
+        tx.execute(
+            "INSERT OR IGNORE INTO evicted (in_chat_id) SELECT in_chat_id FROM message WHERE date \
+             < ?1",
+            [before],
+        )
+        .wrap_err("Failed to record pruned messages")?;
+        let num = tx
+            .execute("DELETE FROM message WHERE date < ?1", [before])
+            .wrap_err("Failed to prune messages")?;
+        tx.commit().wrap_err("Failed to commit pruned messages")?;
+
+        self.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .wrap_err("Failed to checkpoint WAL")?;
+
+        Ok(num)
+    }
+
+    /// Record a user's rating of a quote, replacing their previous one, and
+    /// return the quote's new total
+    pub fn rate(&self, in_chat_id: i64, user_id: i64, score: i64) -> Result<i64> {
+        self.execute(
+            "INSERT OR REPLACE INTO rating (in_chat_id, user_id, score) VALUES (?1, ?2, ?3)",
+            params![in_chat_id, user_id, score],
+        )
+        .wrap_err("Failed to record rating")?;
+
         self.query_row(
-            "SELECT EXISTS(SELECT 1 FROM message WHERE in_chat_id = ?1)",
+            "UPDATE message SET rating = (SELECT SUM(score) FROM rating WHERE in_chat_id = ?1) \
+             WHERE in_chat_id = ?1 RETURNING rating",
             [in_chat_id],
-            |res| res.get(0),
+            |row| row.get::<_, i64>(0),
         )
-        .wrap_err("Failed to check if message exists")
+        .optional()
+        .wrap_err("Failed to update rating")?
+        .unwrap_or_default()
+        .pipe(Ok)
     }
-}
 
-impl Deref for Messages {
-    type Target = Connection;
+    /// Record quotes as just served in an inline answer, by in-chat id
+    pub fn mark_served(&self, in_chat_ids: &[i64]) -> Result<()> {
+        if in_chat_ids.is_empty() {
+            return Ok(());
+        }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        self.execute(
+            &format!(
+                "UPDATE message SET last_served_at = unixepoch() WHERE in_chat_id IN ({})",
+                vec!["?"; in_chat_ids.len()].join(", ")
+            ),
+            params_from_iter(in_chat_ids),
+        )
+        .wrap_err("Failed to mark quotes as served")
+        .map(|_| ())
     }
-}
 
-impl DerefMut for Messages {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    /// Permanently remove messages until at most `max_rows` are left, those
+    /// never served first, then those served least recently, oldest first.
+    /// Their ids are remembered so populating doesn't fetch them again.
+    pub fn evict_over(&self, max_rows: u64) -> Result<usize> {
+        let stored: u64 = self
+            .query_row("SELECT COUNT(*) FROM message", [], |row| row.get(0))
+            .wrap_err("Failed to count messages")?;
+        let over = stored.saturating_sub(max_rows);
+        if over == 0 {
+            return Ok(0);
+        }
+
+        let tx = self
+            .unchecked_transaction()
+            .wrap_err("Failed to start transaction")?;
+        // NULLs sort first, so never served messages go first while the order
+        // can still use `message_last_served_at`
+        let victims = tx
+            .prepare("SELECT in_chat_id FROM message ORDER BY last_served_at, date LIMIT ?1")?
+            .query_map([over], |row| row.get(0))
+            .wrap_err("Failed to pick messages to evict")?
+            .collect::<rusqlite::Result<Vec<i64>>>()
+            .wrap_err("Failed to collect messages to evict")?
+            .pipe(serde_json::Value::from)
+            .to_string();
+        tx.execute(
+            "INSERT OR IGNORE INTO evicted (in_chat_id) SELECT value FROM json_each(?1)",
+            [&victims],
+        )
+        .wrap_err("Failed to record evicted messages")?;
+        let num = tx
+            .execute(
+                "DELETE FROM message WHERE in_chat_id IN (SELECT value FROM json_each(?1))",
+                [&victims],
+            )
+            .wrap_err("Failed to evict messages")?;
+        tx.commit().wrap_err("Failed to commit evicted messages")?;
+
+        if num > 0 {
+            info!("Evicted {num} message(s) to stay under {max_rows}");
+        }
+
+        Ok(num)
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MessageRecord {
-    pub id: i64,
-    pub in_chat_id: i64,
-    pub text: Option<String>,
-    pub is_forwarded: bool,
-    pub raw: Vec<u8>,
-}
+    /// Origins of servable forwards whose name isn't known yet
+    pub fn unnamed_origins(&self) -> Result<Vec<i64>> {
+        self.prepare(
+            "SELECT DISTINCT forward_origin FROM message WHERE forward_origin IS NOT NULL AND \
+             forward_name IS NULL AND servable AND deleted_at IS NULL",
+        )?
+        .query_map([], |row| row.get(0))
+        .wrap_err("Failed to get unnamed origins")?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .wrap_err("Failed to collect unnamed origins")
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SearchResult {
-    pub in_chat_id: i64,
-    pub text: String,
-}
+    /// Name forwards from `origin` that don't have a name yet
+    pub fn set_forward_name(&self, origin: i64, name: &str) -> Result<usize> {
+        self.execute(
+            "UPDATE message SET forward_name = ?2 WHERE forward_origin = ?1 AND forward_name IS NULL",
+            params![origin, name],
+        )
+        .wrap_err("Failed to set forward name")
+    }
 
-impl MessageRecord {
-    // pub fn get_raw(&self) -> Result<Message, serde_json::Error> {
-    //     serde_json::from_slice(&self.raw)
-    // }
+    /// Update the total reactions of a message, by its TDLib id
+    pub fn set_reactions(&self, id: i64, reactions: i64) -> Result<usize> {
+        self.execute(
+            "UPDATE message SET reactions = ?2 WHERE id = ?1",
+            params![id, reactions],
+        )
+        .wrap_err("Failed to update reactions")
+    }
 
-    pub fn from_raw(msg: Message, in_chat_id: i64) -> Result<Self, serde_json::Error> {
-        let text = match msg.content() {
-            MessageContent::MessageText(text) => text.text().text().to_owned().pipe(Some),
-            _ => None,
-        };
+    /// Record a quote being sent by a user through an inline query. Telegram may
+    /// deliver the same send twice on reconnects, so a send identical to one
+    /// recorded in the last `dedup_secs` seconds is ignored. Returns whether the
+    /// send was recorded.
+    pub fn record_send(&self, user_id: i64, in_chat_id: i64, dedup_secs: u64) -> Result<bool> {
+        self.execute(
+            "INSERT INTO send (user_id, in_chat_id, sent_at) SELECT ?1, ?2, unixepoch() WHERE NOT \
+             EXISTS (SELECT 1 FROM send WHERE user_id = ?1 AND in_chat_id = ?2 AND sent_at > \
+             unixepoch() - ?3)",
+            params![user_id, in_chat_id, dedup_secs],
+        )
+        .wrap_err("Failed to record send")
+        .map(|num| num > 0)
+    }
 
-        Self {
-            id: msg.id(),
-            in_chat_id,
-            text,
-            is_forwarded: msg.forward_info().is_some(),
-            raw: serde_json::to_vec(&msg)?,
-        }
-        .pipe(Ok)
+    /// The quote last sent by a user and how many of their latest sends are
+    /// that same quote
+    pub fn repeat_streak(&self, user_id: i64) -> Result<Option<(i64, u32)>> {
+        self.query_row(
+            "SELECT last.in_chat_id, (SELECT COUNT(*) FROM send WHERE user_id = ?1 AND id > \
+             COALESCE((SELECT MAX(id) FROM send WHERE user_id = ?1 AND in_chat_id != \
+             last.in_chat_id), 0)) FROM send AS last WHERE last.user_id = ?1 ORDER BY last.id \
+             DESC LIMIT 1",
+            [user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .wrap_err("Failed to get repeat streak")
     }
-}
 
-impl From<SearchResult> for InputInlineQueryResult {
-    fn from(value: SearchResult) -> Self {
-        InputInlineQueryResultArticle::builder()
-            .id(value.in_chat_id.to_string())
-            .description(format!("#{}", value.in_chat_id))
-            .title(value.text.clone())
-            .hide_url(true)
-            .input_message_content(
-                FormattedText::builder()
-                    .text(value.text)
-                    .build()
-                    .pipe(|text| InputMessageText::builder().text(text).build())
-                    .pipe(InputMessageContent::InputMessageText),
+    /// Increase the number of quotes from `chat_id` sent by a user, unless
+    /// they're blocked. Counts not kept per chat go under [`UNSCOPED_CHAT`].
+    pub fn bump_user_count(&self, user_id: i64, chat_id: i64) -> Result<()> {
+        self.execute(
+            "INSERT INTO user_count (user_id, chat_id, count) SELECT ?1, ?2, 1 WHERE NOT EXISTS \
+             (SELECT 1 FROM user WHERE user_id = ?1 AND blocked) ON CONFLICT (user_id, chat_id) \
+             DO UPDATE SET count = count + 1",
+            params![user_id, chat_id],
+        )
+        .wrap_err("Failed to bump user count")
+        .map(|_| ())
+    }
+
+    /// Move counts kept before they were per chat over to `chat_id`, adding
+    /// them to what's counted there already. Returns the number of users whose
+    /// counts moved.
+    pub fn scope_counts(&self, chat_id: i64) -> Result<usize> {
+        let tx = self
+            .unchecked_transaction()
+            .wrap_err("Failed to start transaction")?;
+        let num = tx
+            .execute(
+                "INSERT INTO user_count (user_id, chat_id, count) SELECT user_id, ?1, count FROM \
+                 user_count WHERE chat_id = ?2 ON CONFLICT (user_id, chat_id) DO UPDATE SET count \
+                 = count + excluded.count",
+                params![chat_id, UNSCOPED_CHAT],
             )
-            .build()
-            .pipe(InputInlineQueryResult::Article)
+            .wrap_err("Failed to move unscoped counts")?;
+        tx.execute("DELETE FROM user_count WHERE chat_id = ?1", [UNSCOPED_CHAT])
+            .wrap_err("Failed to delete unscoped counts")?;
+        tx.commit().wrap_err("Failed to commit scoped counts")?;
+
+        Ok(num)
+    }
+
+    /// Set the number of quotes sent by a user back to 0 in every chat,
+    /// returning what it was in total. `None` if the user never sent anything.
+    pub fn reset_user(&self, user_id: i64) -> Result<Option<u64>> {
+        let count = self
+            .query_row(
+                "SELECT SUM(count) FROM user_count WHERE user_id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .wrap_err("Failed to get user count")?;
+
+        self.execute(
+            "UPDATE user_count SET count = 0 WHERE user_id = ?1",
+            [user_id],
+        )
+        .wrap_err("Failed to reset user count")?;
+
+        Ok(count)
+    }
+
+    pub fn is_blocked(&self, user_id: i64) -> Result<bool> {
+        self.query_row(
+            "SELECT EXISTS(SELECT 1 FROM user WHERE user_id = ?1 AND blocked)",
+            [user_id],
+            |res| res.get(0),
+        )
+        .wrap_err("Failed to check if user is blocked")
+    }
+
+    /// Value of a setting changed at runtime, `None` if it was never set
+    pub fn setting(&self, key: &str) -> Result<Option<String>> {
+        self.query_row("SELECT value FROM setting WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()
+        .wrap_err("Failed to get setting")
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        self.execute(
+            "INSERT INTO setting (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET \
+             value = ?2",
+            params![key, value],
+        )
+        .wrap_err("Failed to update setting")
+        .map(|_| ())
+    }
+
+    /// Block or unblock a user from accumulating sends
+    pub fn set_blocked(&self, user_id: i64, blocked: bool) -> Result<()> {
+        self.execute(
+            "INSERT INTO user (user_id, blocked) VALUES (?1, ?2) ON CONFLICT (user_id) DO UPDATE \
+             SET blocked = ?2",
+            params![user_id, blocked],
+        )
+        .wrap_err("Failed to update blocked user")
+        .map(|_| ())
+    }
+
+    /// Whether the user was offered the "switch to PM" button already
+    pub fn is_welcomed(&self, user_id: i64) -> Result<bool> {
+        self.query_row(
+            "SELECT EXISTS(SELECT 1 FROM user WHERE user_id = ?1 AND welcomed)",
+            [user_id],
+            |res| res.get(0),
+        )
+        .wrap_err("Failed to check if user was welcomed")
+    }
+
+    /// Remember the user was offered the "switch to PM" button, `true` if they
+    /// hadn't been before
+    pub fn welcome(&self, user_id: i64) -> Result<bool> {
+        self.execute(
+            "INSERT INTO user (user_id, welcomed) VALUES (?1, TRUE) ON CONFLICT (user_id) DO \
+             UPDATE SET welcomed = TRUE WHERE NOT welcomed",
+            [user_id],
+        )
+        .wrap_err("Failed to welcome user")
+        .map(|num| num > 0)
+    }
+
+    /// Sends and rank of a user among those who sent anything from `chat_id`,
+    /// or from all chats together if `None`. `None` if they haven't sent
+    /// anything yet or are blocked.
+    pub fn user_stats(&self, user_id: i64, chat_id: Option<i64>) -> Result<Option<UserStats>> {
+        self.query_row(
+            &format!(
+                "WITH {COUNTS} SELECT count, (SELECT COUNT(*) FROM counts AS other WHERE \
+                 other.count > counts.count) + 1, (SELECT COUNT(*) FROM counts) FROM counts WHERE \
+                 user_id = ?1"
+            ),
+            params![user_id, chat_id],
+            |row| {
+                UserStats {
+                    count: row.get(0)?,
+                    rank: row.get(1)?,
+                    total_users: row.get(2)?,
+                    nearby: Vec::new(),
+                }
+                .pipe(Ok)
+            },
+        )
+        .optional()
+        .wrap_err("Failed to get user stats")
+    }
+
+    /// Up to `window` users ranked right above and right below the given user,
+    /// and the user itself, best first, counting sends like
+    /// [`Messages::user_stats`]. Empty if the user isn't ranked.
+    pub fn user_rank_context(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        window: u8,
+    ) -> Result<Vec<RankEntry>> {
+        self.prepare(&format!(
+            "WITH {COUNTS}, ranked AS (SELECT user_id, count, RANK() OVER (ORDER BY count DESC) \
+             AS rank, ROW_NUMBER() OVER (ORDER BY count DESC, user_id) AS pos FROM counts), me \
+             AS (SELECT pos FROM ranked WHERE user_id = ?1) SELECT rank, count, user_id = ?1 \
+             FROM ranked, me WHERE ranked.pos BETWEEN me.pos - ?3 AND me.pos + ?3 ORDER BY \
+             ranked.pos"
+        ))?
+        .query_map(params![user_id, chat_id, window], |row| {
+            RankEntry {
+                rank: row.get(0)?,
+                count: row.get(1)?,
+                is_self: row.get(2)?,
+            }
+            .pipe(Ok)
+        })
+        .wrap_err("Failed to get rank context")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .wrap_err("Failed to collect rank context")
+    }
+
+    /// Most sent quotes since the given unix timestamp, with their send count
+    pub fn top_sent(&self, since: i64, limit: u8) -> Result<Vec<(SearchResult, u64)>> {
+        self.prepare(&format!(
+            "SELECT {}, COUNT(*) AS sends FROM send JOIN message ON message.in_chat_id = \
+             send.in_chat_id {} WHERE send.sent_at >= ?1 AND message.text IS NOT NULL GROUP BY \
+             send.in_chat_id ORDER BY sends DESC LIMIT ?2",
+            SearchResult::COLUMNS,
+            SearchResult::JOINS
+        ))?
+        .query_map(params![since, limit], |row| {
+            Ok((SearchResult::from_row(row)?, row.get("sends")?))
+        })
+        .wrap_err("Failed to get top sent quotes")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .wrap_err("Failed to collect top sent quotes")
+    }
+
+    /// Record a user reporting a quote. Each user counts once per quote.
+    /// Returns whether the report is new.
+    pub fn report(&self, in_chat_id: i64, user_id: i64) -> Result<bool> {
+        self.execute(
+            "INSERT OR IGNORE INTO report (in_chat_id, user_id, reported_at) VALUES (?1, ?2, \
+             unixepoch())",
+            params![in_chat_id, user_id],
+        )
+        .wrap_err("Failed to record report")
+        .map(|num| num > 0)
+    }
+
+    /// Quotes still served with the most reports, with their number of
+    /// reports
+    pub fn most_reported(&self, limit: u8) -> Result<Vec<(SearchResult, u64)>> {
+        self.prepare(&format!(
+            "SELECT {}, COUNT(*) AS reports FROM report JOIN message ON message.in_chat_id = \
+             report.in_chat_id {} WHERE message.deleted_at IS NULL GROUP BY report.in_chat_id \
+             ORDER BY reports DESC LIMIT ?1",
+            SearchResult::COLUMNS,
+            SearchResult::JOINS
+        ))?
+        .query_map(params![limit], |row| {
+            Ok((SearchResult::from_row(row)?, row.get("reports")?))
+        })
+        .wrap_err("Failed to get most reported quotes")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .wrap_err("Failed to collect most reported quotes")
+    }
+
+    /// Number of messages matching the filter that could be served
+    pub fn count(&self, filter: &Filter) -> Result<u64> {
+        let condition = filter.condition();
+
+        self.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM message WHERE {} AND {}",
+                condition.sql,
+                filter.servable()
+            ),
+            condition.params(&[]).as_slice(),
+            |row| row.get(0),
+        )
+        .wrap_err("Failed to count messages")
+    }
+
+    /// Write messages matching the filter to `writer`, one row at a time.
+    /// Returns the number of rows written.
+    pub fn export_filtered(
+        &self,
+        mut writer: impl Write,
+        format: ExportFormat,
+        filter: &Filter,
+        dates: DateFormat,
+    ) -> Result<u64> {
+        let condition = filter.condition();
+        let mut stmt = self.prepare(&format!(
+            "SELECT in_chat_id, date, is_forwarded, author_name, media_kind, text FROM message \
+             WHERE {} ORDER BY in_chat_id",
+            condition.sql
+        ))?;
+        let rows = stmt.query_map(condition.params(&[]).as_slice(), |row| {
+            ExportRow {
+                in_chat_id: row.get(0)?,
+                date: row
+                    .get::<_, Option<i64>>(1)?
+                    .map(|date| dates.rfc3339(date)),
+                is_forwarded: row.get(2)?,
+                author_name: row.get(3)?,
+                media_kind: row.get(4)?,
+                text: row.get(5)?,
+            }
+            .pipe(Ok)
+        })?;
+
+        if format == ExportFormat::Csv {
+            writeln!(
+                writer,
+                "in_chat_id,date,is_forwarded,author_name,media_kind,text"
+            )?;
+        }
+
+        let mut num = 0;
+        for row in rows {
+            let row = row.wrap_err("Failed to read message")?;
+
+            match format {
+                ExportFormat::Csv => row.write_csv(&mut writer)?,
+                ExportFormat::JsonLines => {
+                    serde_json::to_writer(&mut writer, &row)?;
+                    writeln!(writer)?;
+                }
+            }
+            num += 1;
+        }
+
+        writer.flush()?;
+
+        Ok(num)
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let mut stats = self
+            .query_row(
+                "SELECT COUNT(*), COUNT(*) FILTER (WHERE is_forwarded = TRUE), COUNT(text), \
+                 COUNT(media_kind), COUNT(deleted_at), MIN(in_chat_id), MAX(in_chat_id) FROM message",
+                [],
+                |row| {
+                    Stats {
+                        total: row.get(0)?,
+                        forwarded: row.get(1)?,
+                        with_text: row.get(2)?,
+                        media: row.get(3)?,
+                        deleted: row.get(4)?,
+                        min_id: row.get(5)?,
+                        max_id: row.get(6)?,
+                        users: 0,
+                        active_senders: 0,
+                        size: 0,
+                    }
+                    .pipe(Ok)
+                },
+            )
+            .wrap_err("Failed to collect stats")?;
+
+        (stats.users, stats.active_senders) = self
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM (SELECT user_id FROM user UNION SELECT user_id FROM \
+                 user_count UNION SELECT user_id FROM send UNION SELECT user_id FROM rating)), \
+                 (SELECT COUNT(DISTINCT user_id) FROM send WHERE sent_at > unixepoch() - ?1)",
+                [ACTIVE_SECS],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .wrap_err("Failed to count users")?;
+
+        stats.size = self
+            .query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get(0),
+            )
+            .wrap_err("Failed to get database size")?;
+
+        Ok(stats)
+    }
+
+    /// Date of the newest stored message, `None` when the table is empty
+    pub fn latest_date(&self) -> Result<Option<i64>> {
+        self.query_row(
+            "SELECT date FROM message ORDER BY id DESC LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .wrap_err("Failed to get the latest message date")
+        .map(Option::flatten)
+    }
+
+    /// Whether no messages are stored at all
+    pub fn is_empty(&self) -> Result<bool> {
+        self.query_row("SELECT NOT EXISTS(SELECT 1 FROM message)", [], |res| {
+            res.get(0)
+        })
+        .wrap_err("Failed to check if database is empty")
+    }
+
+    /// Whether the message was evicted to stay under `max_db_rows` or pruned
+    /// for its age, see [`Messages::evict_over`] and [`Messages::prune_before`]
+    pub fn is_evicted(&self, in_chat_id: i64) -> Result<bool> {
+        self.query_row(
+            "SELECT EXISTS(SELECT 1 FROM evicted WHERE in_chat_id = ?1)",
+            [in_chat_id],
+            |res| res.get(0),
+        )
+        .wrap_err("Failed to check if message was evicted")
+    }
+
+    pub fn exists(&self, in_chat_id: i64) -> Result<bool> {
+        self.query_row(
+            "SELECT EXISTS(SELECT 1 FROM message WHERE in_chat_id = ?1)",
+            [in_chat_id],
+            |res| res.get(0),
+        )
+        .wrap_err("Failed to check if message exists")
+    }
+
+    /// Those of the given TDLib message ids that were never stored
+    pub fn unknown_ids(&self, ids: &[i64]) -> Result<Vec<i64>> {
+        let mut unknown = vec![];
+        for &id in ids {
+            let exists: bool = self
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM message WHERE id = ?1)",
+                    [id],
+                    |res| res.get(0),
+                )
+                .wrap_err("Failed to check if message exists")?;
+            if !exists {
+                unknown.push(id);
+            }
+        }
+
+        Ok(unknown)
+    }
+
+    /// Number of ids in `min_id..=max_id` neither stored nor evicted, i.e. what a populate
+    /// up to `max_id` would have to fetch at most
+    pub fn gap_count(&self, min_id: i64, max_id: i64) -> Result<u64> {
+        let stored: i64 = self
+            .query_row(
+                "SELECT COUNT(*) FROM (SELECT in_chat_id FROM message UNION SELECT in_chat_id \
+                 FROM evicted) WHERE in_chat_id BETWEEN ?1 AND ?2",
+                [min_id, max_id],
+                |row| row.get(0),
+            )
+            .wrap_err("Failed to count gaps")?;
+
+        Ok((max_id - min_id + 1 - stored).max(0) as u64)
+    }
+
+    /// Smallest stored in-chat id, `None` when the table is empty
+    pub fn min_id(&self) -> Result<Option<i64>> {
+        self.query_row("SELECT MIN(in_chat_id) FROM message", [], |row| row.get(0))
+            .wrap_err("Failed to get the smallest id")
+    }
+}
+
+impl Deref for Messages {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Messages {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessageRecord {
+    pub id: i64,
+    pub in_chat_id: i64,
+    pub text: Option<String>,
+    pub is_forwarded: bool,
+    pub raw: Vec<u8>,
+    /// Sending user, only meaningful for group sources where messages are
+    /// posted by members rather than by the channel itself
+    pub author_id: Option<i64>,
+    pub author_name: Option<String>,
+    pub media_kind: Option<MediaKind>,
+    /// Unix timestamp of when the message was sent
+    pub date: i64,
+    /// Id of the message in the same chat this one replied to
+    pub reply_to: Option<i64>,
+    /// Remote id of the smallest preview of photos, stickers and animations
+    pub thumbnail: Option<String>,
+    /// Total reactions of all kinds
+    pub reactions: i64,
+    /// Whether there's any text or media to serve
+    pub servable: bool,
+    /// Remote id of photos, stickers and animations, which inline results
+    /// send without uploading anything
+    pub file_id: Option<String>,
+    /// Name of the user or chat a forwarded message originally came from
+    pub forward_name: Option<String>,
+    /// Id of the user or chat a forwarded message originally came from, see
+    /// [`forward_origin`]
+    pub forward_origin: Option<i64>,
+}
+
+/// How recently a user must have sent a quote to count as an active sender in
+/// [`Stats`]
+const ACTIVE_SECS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub total: u64,
+    pub forwarded: u64,
+    pub with_text: u64,
+    pub media: u64,
+    /// Soft-deleted messages awaiting purge
+    pub deleted: u64,
+    /// Smallest and largest stored in-chat id, `None` when the table is empty
+    pub min_id: Option<i64>,
+    pub max_id: Option<i64>,
+    /// Users the bot knows of, by an inline query, a send, a rating or a block
+    pub users: u64,
+    /// Users who sent a quote within the last 30 days
+    pub active_senders: u64,
+    /// Size of the database in bytes, excluding the WAL
+    pub size: u64,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Messages:     {}", self.total)?;
+        writeln!(f, "Forwarded:    {}", self.forwarded)?;
+        writeln!(f, "With text:    {}", self.with_text)?;
+        writeln!(f, "Without text: {}", self.total - self.with_text)?;
+        writeln!(f, "Media:        {}", self.media)?;
+        writeln!(f, "Deleted:      {}", self.deleted)?;
+        match (self.min_id, self.max_id) {
+            (Some(min), Some(max)) => writeln!(f, "Id range:     {min}..={max}")?,
+            _ => writeln!(f, "Id range:     -")?,
+        }
+        writeln!(f, "Users:        {}", self.users)?;
+        writeln!(f, "Senders 30d:  {}", self.active_senders)?;
+        write!(f, "Size:         {} bytes", self.size)?;
+        if self.forwarded == 0 && self.total > 0 {
+            write!(
+                f,
+                "\n\nWarning: no forwarded messages, nothing is served with the default \
+                 `serve = \"forwarded\"`"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Id of an inline result, namespaced by what the result is so special results
+/// can never collide with stored messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultId {
+    /// `msg:<in-chat id>`
+    Message(i64),
+    /// `stats`, the personal [`UserStats`] card
+    Stats,
+    /// `report:<in-chat id>`, see [`report_result`]
+    Report(i64),
+    /// `nudge`, see [`nudge_result`]
+    Nudge,
+    /// `browse`, see [`browse_result`]
+    Browse,
+    /// `loading`, see [`loading_result`]
+    Loading,
+}
+
+impl Display for ResultId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ResultId::Message(in_chat_id) => write!(f, "msg:{in_chat_id}"),
+            ResultId::Stats => write!(f, "stats"),
+            ResultId::Report(in_chat_id) => write!(f, "report:{in_chat_id}"),
+            ResultId::Nudge => write!(f, "nudge"),
+            ResultId::Browse => write!(f, "browse"),
+            ResultId::Loading => write!(f, "loading"),
+        }
+    }
+}
+
+impl FromStr for ResultId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("msg", id)) => id.parse().map(ResultId::Message).map_err(|_| ()),
+            Some(("report", id)) => id.parse().map(ResultId::Report).map_err(|_| ()),
+            None if s == "stats" => Ok(ResultId::Stats),
+            None if s == "nudge" => Ok(ResultId::Nudge),
+            None if s == "browse" => Ok(ResultId::Browse),
+            None if s == "loading" => Ok(ResultId::Loading),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Result reporting a quote for review by admins once chosen
+pub fn report_result(in_chat_id: i64) -> InputInlineQueryResult {
+    InputInlineQueryResultArticle::builder()
+        .id(ResultId::Report(in_chat_id).to_string())
+        .title(format!("Report quote #{in_chat_id}"))
+        .description("Flag it as inappropriate for admins to review")
+        .hide_url(true)
+        .input_message_content(
+            FormattedText::builder()
+                .text(format!("Reported quote #{in_chat_id} for review"))
+                .build()
+                .pipe(|text| InputMessageText::builder().text(text).build())
+                .pipe(InputMessageContent::InputMessageText),
+        )
+        .build()
+        .pipe(InputInlineQueryResult::Article)
+}
+
+/// Id of an inline result, whatever its kind
+pub fn result_id(result: &InputInlineQueryResult) -> Option<ResultId> {
+    match result {
+        InputInlineQueryResult::Article(article) => article.id(),
+        InputInlineQueryResult::Photo(photo) => photo.id(),
+        InputInlineQueryResult::Sticker(sticker) => sticker.id(),
+        InputInlineQueryResult::Animation(animation) => animation.id(),
+        _ => return None,
+    }
+    .parse()
+    .ok()
+}
+
+/// Callback data of the rating buttons, `rate:<in-chat id>:<score>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub in_chat_id: i64,
+    /// `1` or `-1`
+    pub score: i64,
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "rate:{}:{}", self.in_chat_id, self.score)
+    }
+}
+
+impl FromStr for Rate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (in_chat_id, score) = s
+            .strip_prefix("rate:")
+            .and_then(|s| s.split_once(':'))
+            .ok_or(())?;
+
+        Rate {
+            in_chat_id: in_chat_id.parse().map_err(|_| ())?,
+            score: match score {
+                "1" => 1,
+                "-1" => -1,
+                _ => return Err(()),
+            },
+        }
+        .pipe(Ok)
+    }
+}
+
+/// 👍 and 👎 buttons under a sent quote. Callback data is bytes, which TDLib
+/// wants base64-encoded.
+pub fn rating_buttons(in_chat_id: i64) -> ReplyMarkup {
+    let button = |text: &str, score| {
+        InlineKeyboardButtonTypeCallback::builder()
+            .data(BASE64.encode(Rate { in_chat_id, score }.to_string()))
+            .build()
+            .pipe(InlineKeyboardButtonType::Callback)
+            .pipe(|kind| {
+                InlineKeyboardButton::builder()
+                    .text(text)
+                    .type_(kind)
+                    .build()
+            })
+    };
+
+    ReplyMarkupInlineKeyboard::builder()
+        .rows(vec![vec![button("👍", 1), button("👎", -1)]])
+        .build()
+        .pipe(ReplyMarkup::InlineKeyboard)
+}
+
+/// Card shown to users who keep sending the same quote, asking them to pick
+/// another one
+pub fn nudge_result(in_chat_id: i64, times: u32) -> InputInlineQueryResult {
+    InputInlineQueryResultArticle::builder()
+        .id(ResultId::Nudge.to_string())
+        .title("Time for a different quote?")
+        .description(format!(
+            "You've sent #{in_chat_id} {times} times in a row, sending it again won't count"
+        ))
+        .hide_url(true)
+        .input_message_content(
+            FormattedText::builder()
+                .text(format!(
+                    "I've sent quote #{in_chat_id} {times} times in a row"
+                ))
+                .build()
+                .pipe(|text| InputMessageText::builder().text(text).build())
+                .pipe(InputMessageContent::InputMessageText),
+        )
+        .build()
+        .pipe(InputInlineQueryResult::Article)
+}
+
+/// Placeholder answer while there's nothing to serve, yet or for now
+pub fn loading_result(text: &str) -> InputInlineQueryResult {
+    InputInlineQueryResultArticle::builder()
+        .id(ResultId::Loading.to_string())
+        .title(text)
+        .hide_url(true)
+        .input_message_content(
+            FormattedText::builder()
+                .text(text)
+                .build()
+                .pipe(|text| InputMessageText::builder().text(text).build())
+                .pipe(InputMessageContent::InputMessageText),
+        )
+        .build()
+        .pipe(InputInlineQueryResult::Article)
+}
+
+/// Link to browse all quotes somewhere else. TDLib can't put a URL button on
+/// the answer itself, so it's a result with one instead.
+pub fn browse_result(url: &str, text: &str) -> InputInlineQueryResult {
+    let button = InlineKeyboardButtonTypeUrl::builder()
+        .url(url)
+        .build()
+        .pipe(InlineKeyboardButtonType::Url)
+        .pipe(|kind| {
+            InlineKeyboardButton::builder()
+                .text(text)
+                .type_(kind)
+                .build()
+        });
+
+    InputInlineQueryResultArticle::builder()
+        .id(ResultId::Browse.to_string())
+        .title(text)
+        .description(url)
+        .url(url)
+        .hide_url(true)
+        .reply_markup(
+            ReplyMarkupInlineKeyboard::builder()
+                .rows(vec![vec![button]])
+                .build()
+                .pipe(ReplyMarkup::InlineKeyboard),
+        )
+        .input_message_content(
+            FormattedText::builder()
+                .text(format!("{text}: {url}"))
+                .build()
+                .pipe(|text| InputMessageText::builder().text(text).build())
+                .pipe(InputMessageContent::InputMessageText),
+        )
+        .build()
+        .pipe(InputInlineQueryResult::Article)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FtsCheck {
+    /// Rows of the message table
+    pub rows: u64,
+    /// Messages in the full-text index
+    pub indexed: u64,
+    /// Whether SQLite's integrity check of the index passed
+    pub consistent: bool,
+}
+
+impl FtsCheck {
+    pub fn is_ok(&self) -> bool {
+        self.consistent && self.rows == self.indexed
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserStats {
+    /// Quotes sent by the user
+    pub count: u64,
+    /// 1-based position on the leaderboard
+    pub rank: u64,
+    pub total_users: u64,
+    /// The user and those ranked around them, see
+    /// [`Messages::user_rank_context`]
+    #[serde(default)]
+    pub nearby: Vec<RankEntry>,
+}
+
+/// Anonymous leaderboard entry
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RankEntry {
+    pub rank: u64,
+    pub count: u64,
+    /// Whether this is the user the context was asked for
+    pub is_self: bool,
+}
+
+impl UserStats {
+    /// The stats card, counting quotes with `quotes` in `locale`
+    pub fn into_inline_result(self, quotes: &Forms, locale: &str) -> InputInlineQueryResult {
+        let sent = quotes.count(locale, self.count);
+
+        let mut text = format!(
+            "I've sent {sent}, ranking #{} of {}",
+            self.rank, self.total_users
+        );
+        if self.nearby.len() > 1 {
+            text.push('\n');
+            for entry in &self.nearby {
+                let me = if entry.is_self { " (me)" } else { "" };
+                text += &format!(
+                    "\n#{} - {}{me}",
+                    entry.rank,
+                    quotes.count(locale, entry.count)
+                );
+            }
+        }
+
+        // Whoever is right above, to have something to chase
+        let ahead = self
+            .nearby
+            .iter()
+            .take_while(|entry| !entry.is_self)
+            .last()
+            .filter(|entry| entry.count > self.count);
+        let description = match ahead {
+            Some(ahead) => format!(
+                "#{} of {}, {} behind #{}",
+                self.rank,
+                self.total_users,
+                ahead.count - self.count,
+                ahead.rank
+            ),
+            None if self.rank == 1 => format!("#1 of {}, top of the board", self.total_users),
+            None => format!("#{} of {}", self.rank, self.total_users),
+        };
+
+        InputInlineQueryResultArticle::builder()
+            .id(ResultId::Stats.to_string())
+            .title(format!("You've sent {sent}"))
+            .description(description)
+            .hide_url(true)
+            .input_message_content(
+                FormattedText::builder()
+                    .text(text)
+                    .build()
+                    .pipe(|text| InputMessageText::builder().text(text).build())
+                    .pipe(InputMessageContent::InputMessageText),
+            )
+            .build()
+            .pipe(InputInlineQueryResult::Article)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub in_chat_id: i64,
+    /// `None` for media served without text
+    pub text: Option<String>,
+    pub media_kind: Option<MediaKind>,
+    pub raw: Vec<u8>,
+    /// Text of the message this one replied to, if it's stored
+    pub reply_text: Option<String>,
+    pub thumbnail: Option<String>,
+    /// See [`MessageRecord::file_id`]
+    pub file_id: Option<String>,
+    /// Unix timestamp, `None` for messages stored before dates were
+    pub date: Option<i64>,
+    pub author_name: Option<String>,
+    pub reactions: i64,
+    /// Public link to the message, not stored but filled in by whoever knows
+    /// the chat
+    pub permalink: Option<String>,
+    /// Title and description of article results, rendered from the configured
+    /// templates. The text and `#id` when not filled in.
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Image shown next to article results, e.g. the bot's logo
+    pub icon_url: Option<String>,
+}
+
+impl SearchResult {
+    /// Columns read by [`SearchResult::from_row`], in order
+    const COLUMNS: &'static str = "message.in_chat_id, message.text, message.media_kind, \
+                                   message.raw, reply.text, message.thumbnail, \
+                                   message.file_id, message.date, message.author_name, \
+                                   message.reactions";
+    /// Joins needed by [`SearchResult::COLUMNS`]
+    const JOINS: &'static str = "LEFT JOIN message AS reply ON reply.id = message.reply_to AND \
+                                 reply.deleted_at IS NULL";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        SearchResult {
+            in_chat_id: row.get(0)?,
+            text: row.get(1)?,
+            media_kind: row.get(2)?,
+            raw: row.get(3)?,
+            reply_text: row.get(4)?,
+            thumbnail: row.get(5)?,
+            file_id: row.get(6)?,
+            date: row.get(7)?,
+            author_name: row.get(8)?,
+            reactions: row.get(9)?,
+            permalink: None,
+            title: None,
+            description: None,
+            icon_url: None,
+        }
+        .pipe(Ok)
+    }
+
+    /// Fill in a title or description template, see [`TEMPLATE_PLACEHOLDERS`]
+    pub fn render(&self, template: &str, dates: &DateFormat) -> String {
+        template
+            .replace("{id}", &self.in_chat_id.to_string())
+            .replace(
+                "{date}",
+                &self.date.map(|date| dates.day(date)).unwrap_or_default(),
+            )
+            .replace("{author}", self.author_name.as_deref().unwrap_or_default())
+            .replace("{reactions}", &self.reactions.to_string())
+            .replace("{text}", self.text.as_deref().unwrap_or_default())
+    }
+
+    /// The description, `#id` by default, followed by a snippet of the
+    /// replied message for context
+    fn description(&self) -> String {
+        const SNIPPET_LEN: usize = 50;
+
+        let description = match &self.description {
+            Some(description) => description.clone(),
+            None => format!("#{}", self.in_chat_id),
+        };
+
+        match &self.reply_text {
+            Some(reply) if reply.chars().count() > SNIPPET_LEN => {
+                let snippet = reply.chars().take(SNIPPET_LEN).collect::<String>();
+                format!("{description} ↩ {snippet}…")
+            }
+            Some(reply) => format!("{description} ↩ {reply}"),
+            None => description,
+        }
+    }
+
+    /// Build the inline result, with `footer` appended to the sent text or
+    /// caption unless it's empty, and `buttons` attached to the sent message
+    pub fn into_inline_result(
+        self,
+        footer: &str,
+        buttons: Option<&ReplyMarkup>,
+    ) -> InputInlineQueryResult {
+        if let Some(result) = self.media_result(footer, buttons) {
+            return result;
+        }
+
+        // Media that can't be sent as is, e.g. with a broken raw message, at
+        // least says what it was
+        let text = self.text.clone().unwrap_or_else(|| {
+            format!(
+                "[{}]",
+                self.media_kind.map(|kind| kind.as_str()).unwrap_or("empty")
+            )
+        });
+
+        let mut article = InputInlineQueryResultArticle::builder();
+        if let Some(permalink) = &self.permalink {
+            article.url(permalink);
+        }
+        if let Some(icon_url) = &self.icon_url {
+            article.thumbnail_url(icon_url);
+        }
+        if let Some(buttons) = buttons {
+            article.reply_markup(buttons);
+        }
+
+        article
+            .id(ResultId::Message(self.in_chat_id).to_string())
+            .description(self.description())
+            .title(self.title.as_deref().unwrap_or(&text))
+            .hide_url(true)
+            .input_message_content(
+                FormattedText::builder()
+                    .text(with_footer(&text, footer))
+                    .build()
+                    .pipe(|text| InputMessageText::builder().text(text).build())
+                    .pipe(InputMessageContent::InputMessageText),
+            )
+            .build()
+            .pipe(InputInlineQueryResult::Article)
+    }
+
+    /// The stored file id, or else the one in the raw message
+    fn file_id<'a>(&'a self, file: &'a File) -> &'a str {
+        self.file_id.as_deref().unwrap_or(file.remote().id())
+    }
+
+    /// Build a photo, sticker or animation result out of the stored message.
+    /// `None` for text and other kinds of media, which are sent as articles.
+    fn media_result(
+        &self,
+        footer: &str,
+        buttons: Option<&ReplyMarkup>,
+    ) -> Option<InputInlineQueryResult> {
+        if !matches!(
+            self.media_kind,
+            Some(MediaKind::Photo | MediaKind::Sticker | MediaKind::Animation)
+        ) {
+            return None;
+        }
+
+        let msg = decode_raw(&self.raw).ok()?;
+        let id = ResultId::Message(self.in_chat_id).to_string();
+        let text = self.text.as_deref().unwrap_or_default();
+        let caption = FormattedText::builder()
+            .text(with_footer(text, footer))
+            .build();
+
+        match msg.content() {
+            MessageContent::MessagePhoto(photo) => {
+                let large = photo.photo().sizes().last()?;
+                let file_id = self.file_id(large.photo());
+
+                let mut result = InputInlineQueryResultPhoto::builder();
+                if let Some(buttons) = buttons {
+                    result.reply_markup(buttons);
+                }
+
+                result
+                    .id(id)
+                    .title(text)
+                    .description(self.description())
+                    .photo_url(file_id)
+                    .thumbnail_url(self.thumbnail.as_deref().unwrap_or(file_id))
+                    .photo_width(large.width())
+                    .photo_height(large.height())
+                    .input_message_content(
+                        InputMessagePhoto::builder()
+                            .photo(remote_file(file_id))
+                            .width(large.width())
+                            .height(large.height())
+                            .caption(caption)
+                            .build()
+                            .pipe(InputMessageContent::InputMessagePhoto),
+                    )
+                    .build()
+                    .pipe(InputInlineQueryResult::Photo)
+            }
+            MessageContent::MessageSticker(sticker) => {
+                let sticker = sticker.sticker();
+                let file_id = self.file_id(sticker.sticker());
+
+                let mut result = InputInlineQueryResultSticker::builder();
+                if let Some(buttons) = buttons {
+                    result.reply_markup(buttons);
+                }
+
+                result
+                    .id(id)
+                    .sticker_url(file_id)
+                    .thumbnail_url(self.thumbnail.as_deref().unwrap_or_default())
+                    .sticker_width(sticker.width())
+                    .sticker_height(sticker.height())
+                    .input_message_content(
+                        InputMessageSticker::builder()
+                            .sticker(remote_file(file_id))
+                            .width(sticker.width())
+                            .height(sticker.height())
+                            .build()
+                            .pipe(InputMessageContent::InputMessageSticker),
+                    )
+                    .build()
+                    .pipe(InputInlineQueryResult::Sticker)
+            }
+            MessageContent::MessageAnimation(animation) => {
+                let animation = animation.animation();
+                let file_id = self.file_id(animation.animation());
+                let thumbnail_mime_type = animation
+                    .thumbnail()
+                    .as_ref()
+                    .and_then(|thumb| thumbnail_mime_type(thumb.format()));
+
+                let mut result = InputInlineQueryResultAnimation::builder();
+                if let Some(buttons) = buttons {
+                    result.reply_markup(buttons);
+                }
+
+                result
+                    .id(id)
+                    .title(text)
+                    .thumbnail_url(self.thumbnail.as_deref().unwrap_or_default())
+                    .thumbnail_mime_type(thumbnail_mime_type.unwrap_or_default())
+                    .video_url(file_id)
+                    .video_mime_type(animation.mime_type())
+                    .video_duration(animation.duration())
+                    .video_width(animation.width())
+                    .video_height(animation.height())
+                    .input_message_content(
+                        InputMessageAnimation::builder()
+                            .animation(remote_file(file_id))
+                            .duration(animation.duration())
+                            .width(animation.width())
+                            .height(animation.height())
+                            .caption(caption)
+                            .build()
+                            .pipe(InputMessageContent::InputMessageAnimation),
+                    )
+                    .build()
+                    .pipe(InputInlineQueryResult::Animation)
+            }
+            _ => return None,
+        }
+        .pipe(Some)
+    }
+}
+
+/// MIME type of an animation thumbnail, `None` for formats inline results
+/// can't use
+fn thumbnail_mime_type(format: &ThumbnailFormat) -> Option<&'static str> {
+    match format {
+        ThumbnailFormat::Jpeg(_) => Some("image/jpeg"),
+        ThumbnailFormat::Gif(_) => Some("image/gif"),
+        ThumbnailFormat::Mpeg4(_) => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+/// Reference an already uploaded file by its remote id
+fn remote_file(id: &str) -> InputFile {
+    InputFileRemote::builder()
+        .id(id)
+        .build()
+        .pipe(InputFile::Remote)
+}
+
+/// Which stored messages are served by `random` and `search`, counted by
+/// `count` or exported by `export_filtered`
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub serve: Serve,
+    /// Only messages with text
+    pub with_text: bool,
+    /// Only messages sent at or after this unix timestamp
+    pub since: Option<i64>,
+    /// Only messages sent before this unix timestamp
+    pub until: Option<i64>,
+    /// Only messages containing this text
+    pub query: Option<String>,
+    /// Only photos, animations and stickers, which clients show as a gallery
+    pub gallery_only: bool,
+    /// Serve photos, animations and stickers without text too
+    pub include_media: bool,
+    /// In-chat ids shown recently, which `random` only picks once everything
+    /// else is used up
+    pub recent: Vec<i64>,
+    /// Only serve forwarded messages originally from these user or chat ids,
+    /// all of them when empty
+    pub forward_origins: Vec<i64>,
+    pub self_forwards: SelfForwards,
+    /// Id of the source chat, which self-forwards come from. Without it they
+    /// are kept as any forward.
+    pub source_chat: Option<i64>,
+    /// Match search queries as whole words instead of anywhere in the text.
+    /// Queries containing CJK are still matched as substrings, since those
+    /// scripts don't separate words with spaces.
+    pub whole_words: bool,
+}
+
+/// Search prepared by [`Messages::search_iter`]
+pub struct SearchRows<'conn> {
+    stmt: Statement<'conn>,
+    condition: Condition,
+    pattern: String,
+    limit: u32,
+}
+
+impl SearchRows<'_> {
+    /// Results, each read from the database as the iterator gets to it
+    pub fn iter(&mut self) -> Result<impl Iterator<Item = Result<SearchResult>> + '_> {
+        let params = self
+            .condition
+            .params(&[(":pattern", &self.pattern), (":limit", &self.limit)]);
+
+        self.stmt
+            .query_map(params.as_slice(), SearchResult::from_row)
+            .wrap_err("Failed to search")?
+            .map(|row| row.wrap_err("Failed to read search result"))
+            .pipe(Ok)
+    }
+}
+
+/// SQL condition along with the values it binds by name, so none of them
+/// need escaping
+struct Condition {
+    sql: String,
+    params: Vec<(&'static str, Value)>,
+}
+
+impl Condition {
+    /// Named parameters of a statement using this condition, its own `extra`
+    /// ones first
+    fn params<'a>(&'a self, extra: &[(&'a str, &'a dyn ToSql)]) -> Vec<(&'a str, &'a dyn ToSql)> {
+        extra
+            .iter()
+            .copied()
+            .chain(
+                self.params
+                    .iter()
+                    .map(|(name, value)| (*name, value as &dyn ToSql)),
+            )
+            .collect()
+    }
+}
+
+impl Filter {
+    /// SQL condition over the `message` table selecting matching rows
+    fn condition(&self) -> Condition {
+        let self_forwards = self
+            .source_chat
+            .map_or(SelfForwards::Keep, |_| self.self_forwards);
+        let forwarded = match self_forwards {
+            SelfForwards::Original => {
+                "(message.is_forwarded AND message.forward_origin IS NOT :source_chat)"
+            }
+            SelfForwards::Keep | SelfForwards::Skip => "message.is_forwarded",
+        };
+        let serve = match self.serve {
+            Serve::Forwarded => forwarded.to_owned(),
+            Serve::All => "TRUE".to_owned(),
+            Serve::Original => format!("NOT {forwarded}"),
+        };
+
+        let mut condition = Condition {
+            sql: format!("message.deleted_at IS NULL AND message.servable AND {serve}"),
+            params: Vec::new(),
+        };
+
+        if self_forwards == SelfForwards::Skip {
+            condition.sql +=
+                " AND NOT (message.is_forwarded AND message.forward_origin IS :source_chat)";
+        }
+
+        if self.with_text {
+            condition.sql += " AND message.text IS NOT NULL";
+        }
+        if let Some(since) = self.since {
+            condition.sql += " AND message.date >= :since";
+            condition.params.push((":since", Value::Integer(since)));
+        }
+        if let Some(until) = self.until {
+            condition.sql += " AND message.date < :until";
+            condition.params.push((":until", Value::Integer(until)));
+        }
+        if self.gallery_only {
+            condition.sql += " AND message.media_kind IN ('photo', 'animation', 'sticker')";
+        }
+        if !self.forward_origins.is_empty() {
+            condition.sql += &format!(
+                " AND (NOT {forwarded} OR message.forward_origin IN (SELECT value FROM \
+                 json_each(:forward_origins)))"
+            );
+            condition.params.push((
+                ":forward_origins",
+                Value::Text(serde_json::Value::from(self.forward_origins.clone()).to_string()),
+            ));
+        }
+        if let Some(query) = &self.query {
+            condition.sql += " AND instr(message.search_text, :query) > 0";
+            condition
+                .params
+                .push((":query", Value::Text(normalize(query))));
+        }
+        // Only bound when some clause above compares against it, since
+        // SQLite rejects named parameters a statement doesn't use
+        if let Some(source_chat) = self
+            .source_chat
+            .filter(|_| condition.sql.contains(":source_chat"))
+        {
+            condition
+                .params
+                .push((":source_chat", Value::Integer(source_chat)));
+        }
+
+        condition
+    }
+
+    /// Like [`Filter::condition`], plus word boundaries around `reg`, already
+    /// normalized, when `whole_words` is set
+    fn search_condition(&self, reg: &str) -> Condition {
+        let mut condition = self.condition();
+
+        if self.whole_words && !reg.chars().any(is_cjk) {
+            condition.sql += " AND word_match(message.search_text, :reg)";
+            condition.params.push((":reg", Value::Text(reg.to_owned())));
+        }
+
+        condition
+    }
+
+    /// SQL condition over the `message` table selecting rows that can be served
+    /// as an inline result: anything with text, plus media that can be sent as
+    /// is when `include_media` is set
+    fn servable(&self) -> &'static str {
+        if self.include_media {
+            "(message.text IS NOT NULL OR message.media_kind IN ('photo', 'animation', 'sticker'))"
+        } else {
+            "message.text IS NOT NULL"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportRow {
+    in_chat_id: i64,
+    /// RFC 3339 in the configured timezone
+    date: Option<String>,
+    is_forwarded: bool,
+    author_name: Option<String>,
+    media_kind: Option<MediaKind>,
+    text: Option<String>,
+}
+
+impl ExportRow {
+    fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        fn field(value: &str) -> Cow<'_, str> {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\"")).into()
+            } else {
+                value.into()
+            }
+        }
+
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            self.in_chat_id,
+            self.date.as_deref().unwrap_or_default(),
+            self.is_forwarded,
+            field(self.author_name.as_deref().unwrap_or_default()),
+            self.media_kind.map(|x| x.as_str()).unwrap_or_default(),
+            field(self.text.as_deref().unwrap_or_default()),
+        )
+    }
+}
+
+/// What's done with messages the source chat forwarded from itself
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfForwards {
+    /// Same as any other forward
+    #[default]
+    Keep,
+    /// Never served
+    Skip,
+    /// Served as if posted directly in the source chat
+    Original,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Serve {
+    /// Only messages forwarded into the chat
+    #[default]
+    Forwarded,
+    /// Every stored message
+    All,
+    /// Only messages posted directly in the chat
+    Original,
+}
+
+/// How search results are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchOrder {
+    /// Different results every time, but the same query never looks the same
+    /// twice
+    Random,
+    /// Newest messages first, stable across identical queries
+    #[default]
+    Recent,
+    /// Best full-text matches first. Queries shorter than 3 characters cannot
+    /// use the index and fall back to `Recent`.
+    Relevance,
+    /// Most reacted messages first, then newest
+    Reactions,
+}
+
+/// Id of the user or chat a forwarded message originally came from. `None`
+/// for messages that weren't forwarded, or whose sender is hidden.
+pub fn forward_origin(msg: &Message) -> Option<i64> {
+    match msg.forward_info().as_ref()?.origin() {
+        MessageForwardOrigin::User(user) => user.sender_user_id(),
+        MessageForwardOrigin::Chat(chat) => chat.sender_chat_id(),
+        MessageForwardOrigin::Channel(channel) => channel.chat_id(),
+        _ => return None,
+    }
+    .pipe(Some)
+}
+
+/// Placeholders filled in by [`SearchResult::render`]
+pub const TEMPLATE_PLACEHOLDERS: &[&str] = &["id", "date", "author", "reactions", "text"];
+
+/// The first `{placeholder}` in `template` that isn't one of
+/// [`TEMPLATE_PLACEHOLDERS`], if any
+pub fn unknown_placeholder(template: &str) -> Option<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| name)
+        .find(|name| !TEMPLATE_PLACEHOLDERS.contains(name))
+}
+
+/// Total reactions in a serialized `MessageInteractionInfo`. Read from JSON
+/// rather than the typed struct so chats without reactions, or a `null` info,
+/// count as 0.
+pub fn reaction_count(interaction_info: &serde_json::Value) -> i64 {
+    interaction_info["reactions"]
+        .as_array()
+        .map_or(0, |reactions| {
+            reactions
+                .iter()
+                .filter_map(|reaction| reaction["total_count"].as_i64())
+                .sum()
+        })
+}
+
+/// Fields of a `Message` left out of its JSON while they hold their default,
+/// which it then can't be read back without
+const DEFAULTED_FIELDS: [&str; 2] = ["sender_id", "content"];
+
+/// Decode a stored raw message. Blobs missing fields, e.g. ones written by
+/// older versions, decode with those at their defaults. Fails for blobs
+/// whose fields no longer match `Message`.
+fn decode_raw(raw: &[u8]) -> Result<Message> {
+    let mut value: serde_json::Value =
+        serde_json::from_slice(raw).wrap_err("Failed to decode raw message")?;
+    if let Some(fields) = value.as_object_mut() {
+        for field in DEFAULTED_FIELDS {
+            fields
+                .entry(field)
+                .or_insert_with(|| serde_json::json!({ "@type": "_Default" }));
+        }
+    }
+
+    serde_json::from_value(value).wrap_err("Failed to decode raw message")
+}
+
+impl MessageRecord {
+    /// The message this record was built from
+    pub fn decode_raw(&self) -> Result<Message> {
+        decode_raw(&self.raw).wrap_err_with(|| format!("Bad raw message of #{}", self.in_chat_id))
+    }
+
+    /// Text not kept by `policy` is dropped, the message is still stored but
+    /// treated as if it had no text
+    pub fn from_raw(
+        msg: Message,
+        in_chat_id: i64,
+        policy: TextPolicy,
+    ) -> Result<Self, serde_json::Error> {
+        // Captions of media messages make perfectly good quotes too
+        let (text, media_kind) = match msg.content() {
+            MessageContent::MessageText(text) => (Some(text.text()), None),
+            MessageContent::MessagePhoto(photo) => (Some(photo.caption()), Some(MediaKind::Photo)),
+            MessageContent::MessageVideo(video) => (Some(video.caption()), Some(MediaKind::Video)),
+            MessageContent::MessageAnimation(animation) => {
+                (Some(animation.caption()), Some(MediaKind::Animation))
+            }
+            MessageContent::MessageDocument(document) => {
+                (Some(document.caption()), Some(MediaKind::Document))
+            }
+            MessageContent::MessageAudio(audio) => (Some(audio.caption()), Some(MediaKind::Audio)),
+            MessageContent::MessageVoiceNote(voice) => {
+                (Some(voice.caption()), Some(MediaKind::VoiceNote))
+            }
+            MessageContent::MessageSticker(_) => (None, Some(MediaKind::Sticker)),
+            MessageContent::MessageVideoNote(_) => (None, Some(MediaKind::VideoNote)),
+            _ => (None, None),
+        };
+        let text = text
+            .map(|text| text.text())
+            .filter(|text| !text.is_empty())
+            .filter(|text| policy.keeps(text))
+            .cloned();
+        let servable = text.is_some() || media_kind.is_some();
+
+        // Cached so results don't have to dig it out of the raw message
+        let thumbnail = match msg.content() {
+            MessageContent::MessagePhoto(photo) => {
+                photo.photo().sizes().first().map(|size| size.photo())
+            }
+            MessageContent::MessageSticker(sticker) => sticker
+                .sticker()
+                .thumbnail()
+                .as_ref()
+                .map(|thumb| thumb.file()),
+            MessageContent::MessageAnimation(animation) => animation
+                .animation()
+                .thumbnail()
+                .as_ref()
+                .map(|thumb| thumb.file()),
+            _ => None,
+        }
+        .map(|file| file.remote().id().to_owned())
+        .filter(|id| !id.is_empty());
+
+        let file_id = match msg.content() {
+            MessageContent::MessagePhoto(photo) => {
+                photo.photo().sizes().last().map(|size| size.photo())
+            }
+            MessageContent::MessageSticker(sticker) => sticker.sticker().sticker().pipe(Some),
+            MessageContent::MessageAnimation(animation) => {
+                animation.animation().animation().pipe(Some)
+            }
+            _ => None,
+        }
+        .map(|file| file.remote().id().to_owned())
+        .filter(|id| !id.is_empty());
+
+        let author_id = match msg.sender_id() {
+            MessageSender::User(user) => user.user_id().pipe(Some),
+            _ => None,
+        };
+
+        // Other origins only have an id, see `Chat::record`
+        let forward_name = msg
+            .forward_info()
+            .as_ref()
+            .and_then(|info| match info.origin() {
+                MessageForwardOrigin::HiddenUser(user) => user.sender_name().to_owned().pipe(Some),
+                MessageForwardOrigin::MessageImport(import) => {
+                    import.sender_name().to_owned().pipe(Some)
+                }
+                _ => None,
+            })
+            .filter(|name| !name.is_empty());
+        let forward_origin = forward_origin(&msg);
+
+        Self {
+            id: msg.id(),
+            in_chat_id,
+            text,
+            is_forwarded: msg.forward_info().is_some(),
+            raw: serde_json::to_vec(&msg)?,
+            author_id,
+            author_name: None,
+            media_kind,
+            date: msg.date().into(),
+            reply_to: (msg.reply_to_message_id() != 0 && msg.reply_in_chat_id() == msg.chat_id())
+                .then(|| msg.reply_to_message_id()),
+            thumbnail,
+            reactions: reaction_count(&serde_json::to_value(msg.interaction_info())?),
+            servable,
+            file_id,
+            forward_name,
+            forward_origin,
+        }
+        .pipe(Ok)
+    }
+}
+
+/// Which texts [`MessageRecord::from_raw`] keeps as quotes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextPolicy {
+    /// Drop texts longer than this many characters
+    pub max_len: Option<usize>,
+    /// Drop texts without a single letter or digit, e.g. only emoji,
+    /// punctuation or whitespace
+    pub require_alphanumeric: bool,
+}
+
+impl TextPolicy {
+    fn keeps(&self, text: &str) -> bool {
+        if self.max_len.map_or(false, |max| text.chars().count() > max) {
+            return false;
+        }
+
+        !self.require_alphanumeric || text.chars().any(char::is_alphanumeric)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    Photo,
+    Video,
+    Animation,
+    Document,
+    Audio,
+    VoiceNote,
+    Sticker,
+    VideoNote,
+}
+
+impl MediaKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Photo => "photo",
+            MediaKind::Video => "video",
+            MediaKind::Animation => "animation",
+            MediaKind::Document => "document",
+            MediaKind::Audio => "audio",
+            MediaKind::VoiceNote => "voice_note",
+            MediaKind::Sticker => "sticker",
+            MediaKind::VideoNote => "video_note",
+        }
+    }
+}
+
+impl ToSql for MediaKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.as_str().to_sql()
+    }
+}
+
+impl FromSql for MediaKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "photo" => MediaKind::Photo,
+            "video" => MediaKind::Video,
+            "animation" => MediaKind::Animation,
+            "document" => MediaKind::Document,
+            "audio" => MediaKind::Audio,
+            "voice_note" => MediaKind::VoiceNote,
+            "sticker" => MediaKind::Sticker,
+            "video_note" => MediaKind::VideoNote,
+            other => {
+                return Err(FromSqlError::Other(
+                    format!("Unknown media kind `{other}`").into(),
+                ))
+            }
+        }
+        .pipe(Ok)
+    }
+}
+
+/// Sent text is plain, without entities, so appending can't shift any offsets
+fn with_footer(text: &str, footer: &str) -> String {
+    match (text, footer) {
+        (text, "") => text.to_owned(),
+        ("", footer) => footer.to_owned(),
+        (text, footer) => format!("{text}\n\n{footer}"),
+    }
+}
+
+/// Whether `words` appears in `text` with no letters or digits right before
+/// or after it, ignoring case
+fn contains_words(text: &str, words: &str) -> bool {
+    let text = text.to_lowercase();
+    let words = words.trim().to_lowercase();
+
+    if words.is_empty() {
+        return true;
+    }
+
+    text.match_indices(&words).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + words.len()..].chars().next();
+
+        !before.map_or(false, char::is_alphanumeric) && !after.map_or(false, char::is_alphanumeric)
+    })
+}
+
+/// Sort key of the row `id` for [`Messages::random_seeded`], smaller first.
+/// Uniform from a hash of `id` keyed by `seed`, turned into an exponential
+/// variate of rate `weight`, so the row sorting first is picked with
+/// probability proportional to its weight. Stable within a build only, as the
+/// standard hasher may change between Rust releases.
+fn random_key(seed: i64, id: i64, weight: f64) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    id.hash(&mut hasher);
+
+    // 53 bits fill a float's mantissa, and the `+ 1` keeps it in (0, 1]
+    let uniform = ((hasher.finish() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+
+    -uniform.ln() / weight
+}
+
+/// Han, kana and hangul, which are written without spaces between words
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11FF}'
+        | '\u{2E80}'..='\u{9FFF}'
+        | '\u{AC00}'..='\u{D7AF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{FF66}'..='\u{FF9F}'
+        | '\u{20000}'..='\u{2FA1F}'
+    )
+}
+
+/// Quotes a trigram may be in for [`Messages::find_similar`] to look for
+/// quotes alike through it
+const MAX_TRIGRAM_QUOTES: usize = 500;
+
+/// Trigrams of the lowercased letters and digits of `text`, so spacing and
+/// punctuation don't count
+fn trigrams(text: &str) -> HashSet<[char; 3]> {
+    let chars = text
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect::<Vec<_>>();
+
+    chars
+        .windows(3)
+        .map(|window| [window[0], window[1], window[2]])
+        .collect()
+}
+
+/// Representative of the group `i` belongs to in a union-find forest
+fn root(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+
+    i
+}
+
+/// What searches match against: lowercased, without accents and with runs of
+/// whitespace collapsed. Only Latin-style diacritics are stripped, so kana
+/// voicing marks and the like survive.
+pub fn normalize(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !('\u{300}'..='\u{36F}').contains(c))
+        .nfc()
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_tdlib::types::{
+        MessageForwardInfo, MessageForwardOriginChannel, MessagePhoto, MessageText, Photo,
+        PhotoSize, RemoteFile,
+    };
+
+    use super::*;
+
+    fn text_content(text: &str) -> MessageContent {
+        MessageText::builder()
+            .text(FormattedText::builder().text(text).build())
+            .build()
+            .pipe(Box::new)
+            .pipe(MessageContent::MessageText)
+    }
+
+    /// A message of the source chat with `content`, its id made up from
+    /// `in_chat_id` the way TDLib does
+    fn message(in_chat_id: i64, content: MessageContent) -> Message {
+        Message::builder()
+            .id(in_chat_id << 20)
+            .chat_id(-1001)
+            .date(1_700_000_000)
+            .content(content)
+            .build()
+    }
+
+    fn remote_file(id: &str) -> File {
+        File::builder()
+            .remote(RemoteFile::builder().id(id).build())
+            .build()
+    }
+
+    fn db() -> Messages {
+        Messages::open(":memory:").unwrap()
+    }
+
+    /// A plain text message posted in the chat, `in_chat_id` standing in for
+    /// its TDLib id too
+    fn quote(in_chat_id: i64, text: &str) -> MessageRecord {
+        MessageRecord {
+            id: in_chat_id,
+            in_chat_id,
+            text: Some(text.to_owned()),
+            is_forwarded: false,
+            raw: Vec::new(),
+            author_id: None,
+            author_name: None,
+            media_kind: None,
+            date: in_chat_id,
+            reply_to: None,
+            thumbnail: None,
+            reactions: 0,
+            servable: true,
+            file_id: None,
+            forward_name: None,
+            forward_origin: None,
+        }
+    }
+
+    fn all() -> Filter {
+        Filter {
+            serve: Serve::All,
+            ..Filter::default()
+        }
+    }
+
+    fn ids(results: &[SearchResult]) -> Vec<i64> {
+        results.iter().map(|result| result.in_chat_id).collect()
+    }
+
+    #[test]
+    fn filter_binds_query_and_dates() {
+        let db = db();
+        db.insert_one(&quote(1, "it's a quote")).unwrap();
+        db.insert_one(&quote(2, "another one")).unwrap();
+        db.insert_one(&quote(3, "it's late")).unwrap();
+
+        let filter = Filter {
+            query: Some("it's".to_owned()),
+            ..all()
+        };
+        assert_eq!(db.count(&filter).unwrap(), 2);
+
+        let filter = Filter {
+            since: Some(2),
+            until: Some(4),
+            ..filter
+        };
+        assert_eq!(ids(&db.recent(10, &filter).unwrap()), [3]);
+
+        // Would end the string literal and comment out the rest if spliced
+        let filter = Filter {
+            query: Some("') > 0 --".to_owned()),
+            ..all()
+        };
+        assert_eq!(db.count(&filter).unwrap(), 0);
+
+        // LIKE wildcards only match themselves
+        db.insert_one(&quote(4, "snake_case 100%")).unwrap();
+        for query in ["_", "%", "e_c", "0%"] {
+            let results = db.search(query, 10, &all(), SearchOrder::Recent).unwrap();
+            assert_eq!(ids(&results), [4], "{query}");
+        }
+    }
+
+    #[test]
+    fn search_iter_reads_lazily() {
+        let db = db();
+        for id in 1..=5 {
+            db.insert_one(&quote(id, "quote")).unwrap();
+        }
+
+        let mut rows = db
+            .search_iter("quote", 100, &all(), SearchOrder::Recent)
+            .unwrap();
+        let first = rows
+            .iter()
+            .unwrap()
+            .take(2)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(ids(&first), [5, 4]);
+        // Read again from the start
+        assert_eq!(rows.iter().unwrap().count(), 5);
+    }
+
+    #[test]
+    fn whole_word_search_binds_query() {
+        let db = db();
+        db.insert_one(&quote(1, "don't panic")).unwrap();
+        db.insert_one(&quote(2, "don'tpanic")).unwrap();
+
+        let filter = Filter {
+            whole_words: true,
+            ..all()
+        };
+        for order in [SearchOrder::Relevance, SearchOrder::Recent] {
+            let results = db.search("don't", 10, &filter, order).unwrap();
+            assert_eq!(ids(&results), [1]);
+        }
+    }
+
+    /// How often each row came first over `seeds` seeds
+    fn first_picks(db: &Messages, seeds: i64) -> HashMap<i64, u32> {
+        let mut picks = HashMap::new();
+        for seed in 0..seeds {
+            let first = db.random_seeded(1, &all(), seed).unwrap()[0].in_chat_id;
+            *picks.entry(first).or_default() += 1;
+        }
+
+        picks
+    }
+
+    #[test]
+    fn random_seeded_is_deterministic() {
+        let db = db();
+        for id in 1..=50 {
+            db.insert_one(&quote(id, &format!("quote {id}"))).unwrap();
+        }
+
+        let first = ids(&db.random_seeded(20, &all(), 42).unwrap());
+        assert_eq!(first.len(), 20);
+        assert_eq!(ids(&db.random_seeded(20, &all(), 42).unwrap()), first);
+        assert_ne!(ids(&db.random_seeded(20, &all(), 43).unwrap()), first);
+    }
+
+    #[test]
+    fn random_seeded_is_uniform() {
+        let db = db();
+        for id in 1..=10 {
+            db.insert_one(&quote(id, &format!("quote {id}"))).unwrap();
+        }
+
+        // 200 each expected, about 13 of standard deviation
+        let picks = first_picks(&db, 2000);
+        assert_eq!(picks.len(), 10);
+        for (id, count) in picks {
+            assert!(
+                (120..=280).contains(&count),
+                "#{id} came first {count} times"
+            );
+        }
+    }
+
+    #[test]
+    fn random_seeded_favors_rated() {
+        let db = db();
+        for id in 1..=10 {
+            db.insert_one(&quote(id, &format!("quote {id}"))).unwrap();
+        }
+        db.rate(1, 1, 10).unwrap();
+
+        // Weighing 11 against 9 others weighing 1, #1 comes first 55% of the time
+        let picks = first_picks(&db, 2000);
+        assert!((950..=1250).contains(&picks[&1]), "{picks:?}");
+    }
+
+    #[test]
+    fn random_seeded_favors_reacted() {
+        let db = db();
+        for id in 1..=10 {
+            let reactions = if id == 1 { 100 } else { 0 };
+            db.insert_one(&MessageRecord {
+                reactions,
+                ..quote(id, &format!("quote {id}"))
+            })
+            .unwrap();
+        }
+
+        // Same odds as a top rating, 11 against 9
+        let picks = first_picks(&db, 2000);
+        assert!((950..=1250).contains(&picks[&1]), "{picks:?}");
+    }
+
+    fn column<T: FromSql>(db: &Messages, column: &str, in_chat_id: i64) -> T {
+        db.query_row(
+            &format!("SELECT {column} FROM message WHERE in_chat_id = ?1"),
+            [in_chat_id],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn insert_keeps_bookkeeping() {
+        let db = db();
+        db.insert_one(&quote(1, "before")).unwrap();
+        db.mark_served(&[1]).unwrap();
+
+        db.insert_one(&quote(1, "after")).unwrap();
+        assert_eq!(column::<String>(&db, "text", 1), "after");
+        assert!(column::<Option<i64>>(&db, "last_served_at", 1).is_some());
+
+        // The index follows the new text
+        let search = |reg| ids(&db.search(reg, 10, &all(), SearchOrder::Relevance).unwrap());
+        assert!(search("before").is_empty());
+        assert_eq!(search("after"), [1]);
+
+        db.delete(&[1]).unwrap();
+        db.insert_one(&quote(1, "again")).unwrap();
+        assert!(column::<Option<i64>>(&db, "deleted_at", 1).is_some());
+    }
+
+    #[test]
+    fn mark_served_marks_all() {
+        let db = db();
+        for id in 1..=3 {
+            db.insert_one(&quote(id, "quote")).unwrap();
+        }
+
+        db.mark_served(&[1, 3]).unwrap();
+        assert!(column::<Option<i64>>(&db, "last_served_at", 1).is_some());
+        assert!(column::<Option<i64>>(&db, "last_served_at", 2).is_none());
+        assert!(column::<Option<i64>>(&db, "last_served_at", 3).is_some());
+    }
+
+    #[test]
+    fn evicted_stay_evicted() {
+        let db = db();
+        for id in 1..=5 {
+            db.insert_one(&quote(id, "quote")).unwrap();
+        }
+        db.mark_served(&[1]).unwrap();
+
+        // Never served and oldest go first
+        assert_eq!(db.evict_over(3).unwrap(), 2);
+        assert!(db.exists(1).unwrap());
+        assert!(db.is_evicted(2).unwrap() && db.is_evicted(3).unwrap());
+        assert!(!db.is_evicted(4).unwrap());
+        assert_eq!(db.gap_count(1, 5).unwrap(), 0);
+
+        // A forced repopulate bringing one back only gets it evicted again
+        db.insert_one(&quote(2, "quote")).unwrap();
+        assert_eq!(db.evict_over(3).unwrap(), 1);
+        assert!(!db.exists(2).unwrap());
+        assert!([1, 4, 5].iter().all(|&id| db.exists(id).unwrap()));
+
+        // Under the cap, nothing is deleted, not even ids evicted before
+        db.insert_one(&quote(3, "quote")).unwrap();
+        assert_eq!(db.evict_over(4).unwrap(), 0);
+        assert!(db.exists(3).unwrap());
+    }
+
+    #[test]
+    fn evict_after_live_insert() {
+        let db = db();
+        for id in 1..=10 {
+            db.insert_one(&quote(id, "quote")).unwrap();
+        }
+        // Plenty of ids evicted or pruned long ago
+        assert_eq!(db.prune_before(6).unwrap(), 5);
+        db.mark_served(&[6, 7, 8]).unwrap();
+
+        // Each insert over the cap only takes the one message it has to
+        db.insert_one(&quote(11, "quote")).unwrap();
+        assert_eq!(db.evict_over(5).unwrap(), 1);
+        assert!(db.is_evicted(9).unwrap() && !db.exists(9).unwrap());
+        assert!([6, 7, 8, 10, 11].iter().all(|&id| db.exists(id).unwrap()));
+    }
+
+    #[test]
+    fn pruned_stay_evicted() {
+        let db = db();
+        for id in 1..=3 {
+            db.insert_one(&quote(id, "quote")).unwrap();
+        }
+
+        // `quote` dates messages by their id
+        assert_eq!(db.prune_before(3).unwrap(), 2);
+        assert!(db.is_evicted(1).unwrap() && db.is_evicted(2).unwrap());
+        assert!(!db.is_evicted(3).unwrap() && db.exists(3).unwrap());
+        assert_eq!(db.gap_count(1, 3).unwrap(), 0);
+    }
+
+    #[test]
+    fn rating_survives_reinsert() {
+        let db = db();
+        db.insert_one(&quote(1, "quote")).unwrap();
+        assert_eq!(db.rate(1, 10, 1).unwrap(), 1);
+        assert_eq!(db.rate(1, 11, 1).unwrap(), 2);
+        // Rating again replaces the user's previous score
+        assert_eq!(db.rate(1, 11, -1).unwrap(), 0);
+        assert_eq!(db.rate(1, 11, 1).unwrap(), 2);
+
+        // As edits and repopulates do
+        db.insert_one(&quote(1, "edited")).unwrap();
+        assert_eq!(column::<i64>(&db, "rating", 1), 2);
+        assert_eq!(db.rate(1, 12, 1).unwrap(), 3);
+    }
+
+    #[test]
+    fn pruned_stay_known() {
+        let db = db();
+        db.insert_one(&quote(1, "quote")).unwrap();
+        db.insert_one(&MessageRecord {
+            text: None,
+            servable: false,
+            raw: b"{}".to_vec(),
+            ..quote(2, "")
+        })
+        .unwrap();
+
+        assert_eq!(db.prune_unservable().unwrap(), 1);
+        assert_eq!(db.prune_unservable().unwrap(), 0);
+        assert!(column::<Vec<u8>>(&db, "raw", 2).is_empty());
+
+        // Purging leaves the tombstone, so populating still skips the id
+        assert_eq!(db.purge_deleted().unwrap(), 0);
+        assert!(db.exists(2).unwrap());
+        assert_eq!(db.count(&all()).unwrap(), 1);
+    }
+
+    #[test]
+    fn forward_origins_filter_when_serving() {
+        let db = db();
+        let forward = |in_chat_id, origin| MessageRecord {
+            is_forwarded: true,
+            forward_origin: origin,
+            ..quote(in_chat_id, "quote")
+        };
+        db.insert_one(&quote(1, "quote")).unwrap();
+        db.insert_one(&forward(2, Some(-100))).unwrap();
+        db.insert_one(&forward(3, Some(42))).unwrap();
+        // Hidden senders have no id to allow
+        db.insert_one(&forward(4, None)).unwrap();
+
+        let mut filter = all();
+        assert_eq!(db.count(&filter).unwrap(), 4);
+
+        filter.forward_origins = vec![-100];
+        assert_eq!(ids(&db.recent(10, &filter).unwrap()), [2, 1]);
+
+        // Changing the allowlist takes effect without repopulating
+        filter.forward_origins = vec![-100, 42];
+        assert_eq!(ids(&db.recent(10, &filter).unwrap()), [3, 2, 1]);
+    }
+
+    #[test]
+    fn self_forwards() {
+        const SOURCE: i64 = -1001;
+
+        let db = db();
+        db.insert_one(&quote(1, "posted")).unwrap();
+        for (in_chat_id, origin) in [(2, SOURCE), (3, -1002)] {
+            db.insert_one(&MessageRecord {
+                is_forwarded: true,
+                forward_origin: Some(origin),
+                ..quote(in_chat_id, "forwarded")
+            })
+            .unwrap();
+        }
+
+        let served = |serve, self_forwards, source_chat| {
+            let filter = Filter {
+                serve,
+                self_forwards,
+                source_chat,
+                ..Filter::default()
+            };
+            ids(&db.recent(10, &filter).unwrap())
+        };
+
+        assert_eq!(
+            served(Serve::Forwarded, SelfForwards::Keep, Some(SOURCE)),
+            [3, 2]
+        );
+        assert_eq!(
+            served(Serve::Forwarded, SelfForwards::Skip, Some(SOURCE)),
+            [3]
+        );
+        assert_eq!(served(Serve::All, SelfForwards::Skip, Some(SOURCE)), [3, 1]);
+        assert_eq!(
+            served(Serve::Forwarded, SelfForwards::Original, Some(SOURCE)),
+            [3]
+        );
+        assert_eq!(
+            served(Serve::Original, SelfForwards::Original, Some(SOURCE)),
+            [2, 1]
+        );
+        // Nothing to compare the source chat against
+        assert_eq!(
+            served(Serve::All, SelfForwards::Original, Some(SOURCE)),
+            [3, 2, 1]
+        );
+        // Unknown source chat, as in the CLI
+        assert_eq!(served(Serve::Forwarded, SelfForwards::Skip, None), [3, 2]);
+
+        // The allowlist doesn't hide self-forwards served as originals
+        let filter = Filter {
+            serve: Serve::All,
+            self_forwards: SelfForwards::Original,
+            source_chat: Some(SOURCE),
+            forward_origins: vec![-1003],
+            ..Filter::default()
+        };
+        assert_eq!(ids(&db.recent(10, &filter).unwrap()), [2, 1]);
+    }
+
+    #[test]
+    fn synthetic_self_forward() {
+        const SOURCE: i64 = -1001;
+
+        let origin = MessageForwardOriginChannel::builder()
+            .chat_id(SOURCE)
+            .message_id(1 << 20)
+            .build()
+            .pipe(MessageForwardOrigin::Channel);
+        let msg = Message::builder()
+            .id(2 << 20)
+            .chat_id(SOURCE)
+            .date(1_700_000_000)
+            .forward_info(MessageForwardInfo::builder().origin(origin).build())
+            .content(text_content("said it before"))
+            .build();
+
+        let record = MessageRecord::from_raw(msg, 2, TextPolicy::default()).unwrap();
+        assert!(record.is_forwarded);
+        assert_eq!(record.forward_origin, Some(SOURCE));
+
+        let db = db();
+        db.insert_one(&record).unwrap();
+        let filter = |self_forwards| Filter {
+            self_forwards,
+            source_chat: Some(SOURCE),
+            ..Filter::default()
+        };
+        assert_eq!(db.count(&filter(SelfForwards::Keep)).unwrap(), 1);
+        assert_eq!(db.count(&filter(SelfForwards::Skip)).unwrap(), 0);
+    }
+
+    #[test]
+    fn unnamed_origins_get_named() {
+        let db = db();
+        for (in_chat_id, origin, name) in [(1, 42, None), (2, 42, None), (3, 43, Some("Known"))] {
+            db.insert_one(&MessageRecord {
+                is_forwarded: true,
+                forward_origin: Some(origin),
+                forward_name: name.map(str::to_owned),
+                ..quote(in_chat_id, "quote")
+            })
+            .unwrap();
+        }
+
+        assert_eq!(db.unnamed_origins().unwrap(), [42]);
+        assert_eq!(db.set_forward_name(42, "Someone").unwrap(), 2);
+        assert!(db.unnamed_origins().unwrap().is_empty());
+        assert_eq!(ids(&db.by_author("someone", 10, &all()).unwrap()), [2, 1]);
+    }
+
+    #[test]
+    fn similar_despite_common_trigrams() {
+        let db = db();
+        db.insert_one(&quote(1, "And the road goes ever on and on"))
+            .unwrap();
+        // Every filler shares `and` with the others, too many to look at
+        for id in 2..=MAX_TRIGRAM_QUOTES as i64 + 100 {
+            let noise = (id as u64).wrapping_mul(2_654_435_761) % (1 << 32);
+            db.insert_one(&quote(id, &format!("and {noise:08x}")))
+                .unwrap();
+        }
+        db.insert_one(&quote(1000, "and the road goes ever on, and on!"))
+            .unwrap();
+
+        let groups = db.find_similar(0.8).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [1, 1000]
+        );
+    }
+
+    #[test]
+    fn welcomed_once() {
+        let db = db();
+        db.set_blocked(7, false).unwrap();
+        assert!(!db.is_welcomed(7).unwrap());
+
+        assert!(db.welcome(7).unwrap());
+        assert!(db.is_welcomed(7).unwrap());
+        assert!(!db.welcome(7).unwrap());
+
+        // Users never seen before get a row of their own
+        assert!(db.welcome(8).unwrap());
+        assert!(!db.welcome(8).unwrap());
+        assert!(!db.is_blocked(8).unwrap());
+    }
+
+    #[test]
+    fn user_counts() {
+        let db = db();
+        db.insert_one(&quote(10, "quote")).unwrap();
+        for (user_id, in_chat_id) in [(1, 10), (1, 11), (2, 10)] {
+            db.record_send(user_id, in_chat_id, 0).unwrap();
+            db.bump_user_count(user_id, UNSCOPED_CHAT).unwrap();
+        }
+        db.execute(
+            "INSERT INTO send (user_id, in_chat_id, sent_at) VALUES (3, 10, unixepoch() - ?1)",
+            [ACTIVE_SECS + 60],
+        )
+        .unwrap();
+        db.bump_user_count(3, UNSCOPED_CHAT).unwrap();
+
+        // Users who only queried or rated count too, but aren't senders
+        db.welcome(4).unwrap();
+        db.rate(10, 5, 1).unwrap();
+        db.rate(10, 1, 1).unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!((stats.users, stats.active_senders), (5, 2));
+    }
+
+    #[test]
+    fn per_chat_counts() {
+        let db = db();
+        // Counted before counts were per chat
+        db.bump_user_count(1, UNSCOPED_CHAT).unwrap();
+        for _ in 0..3 {
+            db.bump_user_count(2, UNSCOPED_CHAT).unwrap();
+        }
+
+        assert_eq!(db.scope_counts(-100).unwrap(), 2);
+        assert_eq!(db.scope_counts(-100).unwrap(), 0);
+        db.bump_user_count(1, -100).unwrap();
+        db.bump_user_count(1, -200).unwrap();
+        db.bump_user_count(1, -200).unwrap();
+
+        let stats = |user_id, chat_id| {
+            db.user_stats(user_id, chat_id)
+                .unwrap()
+                .map(|stats| (stats.count, stats.rank, stats.total_users))
+        };
+        assert_eq!(stats(1, Some(-100)), Some((2, 2, 2)));
+        assert_eq!(stats(2, Some(-100)), Some((3, 1, 2)));
+        assert_eq!(stats(1, Some(-200)), Some((2, 1, 1)));
+        assert_eq!(stats(2, Some(-200)), None);
+        // All chats together
+        assert_eq!(stats(1, None), Some((4, 1, 2)));
+        assert_eq!(db.user_rank_context(2, None, 1).unwrap().len(), 2);
+
+        // Blocked users aren't counted in any chat
+        db.set_blocked(2, true).unwrap();
+        db.bump_user_count(2, -200).unwrap();
+        assert_eq!(stats(2, None), None);
+        assert_eq!(stats(1, Some(-100)), Some((2, 1, 1)));
+        db.set_blocked(2, false).unwrap();
+        assert_eq!(stats(2, None), Some((3, 2, 2)));
+
+        assert_eq!(db.reset_user(1).unwrap(), Some(4));
+        assert_eq!(stats(1, None), None);
+        assert_eq!(db.reset_user(3).unwrap(), None);
+    }
+
+    #[test]
+    fn global_counts_migrated() {
+        let db = Messages(Connection::open_in_memory().unwrap());
+        db.register_functions().unwrap();
+        let migrations = Migrations::new(Messages::migrations());
+        let mut conn = db.0;
+        let before = Messages::latest_schema_version() - 1;
+        migrations.to_version(&mut conn, before).unwrap();
+        conn.execute(
+            "INSERT INTO user (user_id, count, blocked) VALUES (1, 5, FALSE), (2, 0, TRUE)",
+            [],
+        )
+        .unwrap();
+
+        migrations.to_latest(&mut conn).unwrap();
+        let db = Messages(conn);
+        assert_eq!(db.user_stats(1, None).unwrap().unwrap().count, 5);
+        assert!(db.is_blocked(2).unwrap());
+        assert_eq!(db.scope_counts(-100).unwrap(), 1);
+        assert_eq!(db.user_stats(1, Some(-100)).unwrap().unwrap().count, 5);
+    }
+
+    #[test]
+    fn photo_caption_is_quote() {
+        let sizes = ["small", "large"]
+            .map(|id| PhotoSize::builder().photo(remote_file(id)).build())
+            .to_vec();
+        let photo = MessagePhoto::builder()
+            .photo(Photo::builder().sizes(sizes).build())
+            .caption(FormattedText::builder().text("Caption as a quote").build())
+            .build()
+            .pipe(MessageContent::MessagePhoto);
+
+        let record = MessageRecord::from_raw(message(5, photo), 5, TextPolicy::default()).unwrap();
+        assert_eq!(record.text.as_deref(), Some("Caption as a quote"));
+        assert_eq!(record.media_kind, Some(MediaKind::Photo));
+        assert_eq!(record.thumbnail.as_deref(), Some("small"));
+        assert_eq!(record.file_id.as_deref(), Some("large"));
+        assert!(record.servable);
+
+        let db = db();
+        db.insert_one(&record).unwrap();
+        let found = db
+            .search("caption", 10, &all(), SearchOrder::Recent)
+            .unwrap();
+        assert_eq!(ids(&found), [5]);
+    }
+
+    #[test]
+    fn duplicated_send_counts_once() {
+        let db = db();
+        let send = |user_id, in_chat_id| {
+            if db.record_send(user_id, in_chat_id, 60).unwrap() {
+                db.bump_user_count(user_id, UNSCOPED_CHAT).unwrap();
+            }
+        };
+
+        send(1, 10);
+        // Delivered again on reconnect
+        send(1, 10);
+        assert_eq!(db.user_stats(1, None).unwrap().unwrap().count, 1);
+
+        // Another quote, or another user, is no duplicate
+        send(1, 11);
+        send(2, 10);
+        assert_eq!(db.user_stats(1, None).unwrap().unwrap().count, 2);
+        assert_eq!(db.user_stats(2, None).unwrap().unwrap().count, 1);
+
+        // Nor is the same quote once the window has passed
+        assert!(db.record_send(3, 10, 0).unwrap());
+        assert!(db.record_send(3, 10, 0).unwrap());
+    }
+
+    #[test]
+    fn media_only_served_with_include_media() {
+        let db = db();
+        db.insert_one(&quote(1, "text")).unwrap();
+        for (in_chat_id, kind) in [(2, MediaKind::Sticker), (3, MediaKind::Video)] {
+            db.insert_one(&MessageRecord {
+                text: None,
+                media_kind: Some(kind),
+                file_id: Some(format!("file-{in_chat_id}")),
+                ..quote(in_chat_id, "")
+            })
+            .unwrap();
+        }
+
+        assert_eq!(ids(&db.recent(10, &all()).unwrap()), [1]);
+
+        // Videos can't be sent as an inline result on their own
+        let filter = Filter {
+            include_media: true,
+            ..all()
+        };
+        assert_eq!(ids(&db.recent(10, &filter).unwrap()), [2, 1]);
+        assert_eq!(db.count(&filter).unwrap(), 2);
+        let mut picked = ids(&db.random(10, &filter).unwrap());
+        picked.sort_unstable();
+        assert_eq!(picked, [1, 2]);
+    }
+
+    #[test]
+    fn text_just_over_max_len_dropped() {
+        let policy = TextPolicy {
+            max_len: Some(10),
+            ..TextPolicy::default()
+        };
+        let record = |text: &str| {
+            MessageRecord::from_raw(message(1, text_content(text)), 1, policy).unwrap()
+        };
+
+        let at_limit = record("ten chars!");
+        assert_eq!(at_limit.text.as_deref(), Some("ten chars!"));
+        assert!(at_limit.servable);
+
+        // Kept but with nothing to serve
+        let over = record("eleven char");
+        assert_eq!(over.text, None);
+        assert!(!over.servable);
+
+        // Counted in characters, not bytes
+        assert!(record("éééééééééé").servable);
+        assert!(!record("ééééééééééé").servable);
+    }
+
+    #[test]
+    fn normalized() {
+        assert_eq!(normalize("  Crème   BRÛLÉE\n"), "creme brulee");
+        // Kana voicing marks aren't accents
+        assert_eq!(normalize("ガギグ"), "ガギグ");
+        assert_eq!(normalize("😂😂"), "😂😂");
+        assert_eq!(normalize(" \t "), "");
+    }
+
+    #[test]
+    fn symbol_only_dropped() {
+        let policy = TextPolicy {
+            require_alphanumeric: true,
+            ..TextPolicy::default()
+        };
+        let text = |text: &str, policy| {
+            MessageRecord::from_raw(message(1, text_content(text)), 1, policy)
+                .unwrap()
+                .text
+        };
+
+        for symbols in ["😂😂😂", "?!...", "👍 !", "   "] {
+            assert_eq!(text(symbols, policy), None, "{symbols:?}");
+            assert!(text(symbols, TextPolicy::default()).is_some());
+        }
+        for quote in ["ok 👍", "42!", "すごい"] {
+            assert_eq!(text(quote, policy).as_deref(), Some(quote));
+        }
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let msg = message(7, text_content("round and round"));
+        let record = MessageRecord::from_raw(msg.clone(), 7, TextPolicy::default()).unwrap();
+
+        let decoded = record.decode_raw().unwrap();
+        assert_eq!(decoded.id(), msg.id());
+        assert_eq!(decoded.date(), msg.date());
+        assert_eq!(
+            serde_json::to_value(decoded.content()).unwrap(),
+            serde_json::to_value(msg.content()).unwrap()
+        );
+
+        // Through the database too
+        let db = db();
+        db.insert_one(&record).unwrap();
+        let stored = db.recent(1, &all()).unwrap().remove(0);
+        assert_eq!(decode_raw(&stored.raw).unwrap().id(), msg.id());
+
+        // Blobs missing fields decode with them at their defaults
+        let old = decode_raw(b"{\"@type\":\"message\",\"id\":7340032}").unwrap();
+        assert_eq!(old.id(), 7 << 20);
+        assert!(old.content()._is_default());
+
+        // Tombstones and blobs of incompatible versions fail rather than panic
+        for raw in [&b""[..], b"{\"@type\":\"message\",\"id\":\"nope\"}"] {
+            let bad = MessageRecord {
+                raw: raw.to_vec(),
+                ..quote(7, "")
+            };
+            let e = bad.decode_raw().unwrap_err();
+            assert!(format!("{e:#}").starts_with("Bad raw message of #7"));
+        }
     }
 }
@@ -0,0 +1,137 @@
+//! Failures the bot tells apart, e.g. to decide between retrying and giving
+//! up. Everything ends up in an `eyre` report at the top, where the kind can
+//! still be found with [`Error::find`].
+//!
+//! Only code whose callers branch on the kind of failure returns [`Error`]:
+//! config validation, fetching messages while populating and requests sent
+//! through [`crate::tdlib::Retrier`]. The database layer, commands and the CLI
+//! only ever log failures or show them to whoever asked, so they return
+//! `eyre` reports with context instead.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::Report;
+use rusqlite::ErrorCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Config that can't be loaded or makes no sense
+    #[error("Invalid config: {0}")]
+    Config(String),
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    /// Request refused by Telegram or TDLib, `code` being an HTTP-like status
+    #[error("Telegram error {code}: {message}")]
+    Telegram { code: i32, message: String },
+    /// Failing to talk to TDLib at all, e.g. a request that couldn't be sent
+    /// or an answer that couldn't be read
+    #[error("TDLib client error: {0}")]
+    Client(rust_tdlib::errors::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Path handed to a library that only takes UTF-8 ones
+    #[error("Path {} isn't valid UTF-8", .0.display())]
+    NonUtf8Path(PathBuf),
+}
+
+impl From<rust_tdlib::errors::Error> for Error {
+    fn from(err: rust_tdlib::errors::Error) -> Self {
+        match err {
+            rust_tdlib::errors::Error::TDLibError(err) => Error::Telegram {
+                code: err.code(),
+                message: err.message().to_owned(),
+            },
+            err => Error::Client(err),
+        }
+    }
+}
+
+impl Error {
+    /// The first [`Error`] `report` was made of, if any
+    pub fn find(report: &Report) -> Option<&Error> {
+        report.chain().find_map(|err| err.downcast_ref())
+    }
+
+    /// Whether the same request may succeed later. Config errors and requests
+    /// Telegram refused as bad never will.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Config(_) => false,
+            Error::Db(rusqlite::Error::SqliteFailure(err, _)) => matches!(
+                err.code,
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked
+            ),
+            Error::Db(_) => false,
+            Error::Telegram { code, .. } => *code == 429 || *code >= 500,
+            // Retries are bounded, and the client may well recover meanwhile
+            Error::Client(_) => true,
+            Error::Io(_) => true,
+            Error::NonUtf8Path(_) => false,
+        }
+    }
+}
+
+/// `path` as a string, for libraries that only take UTF-8 paths
+pub fn utf8_path(path: &Path) -> Result<&str, Error> {
+    path.to_str()
+        .ok_or_else(|| Error::NonUtf8Path(path.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tap::Pipe;
+
+    use super::*;
+
+    #[test]
+    fn find_through_context() {
+        let report = Report::from(Error::Telegram {
+            code: 400,
+            message: "MESSAGE_ID_INVALID".to_owned(),
+        })
+        .wrap_err("Failed to get message");
+
+        assert!(matches!(
+            Error::find(&report),
+            Some(Error::Telegram { code: 400, .. })
+        ));
+        assert!(!Error::find(&report).unwrap().is_transient());
+        assert!(Error::find(&color_eyre::eyre::eyre!("Something else")).is_none());
+    }
+
+    #[test]
+    fn from_tdlib() {
+        let err = rust_tdlib::types::Error::builder()
+            .code(400)
+            .message("MESSAGE_ID_INVALID")
+            .build()
+            .pipe(rust_tdlib::errors::Error::TDLibError)
+            .pipe(Error::from);
+        assert!(matches!(
+            err,
+            Error::Telegram { code: 400, ref message } if message == "MESSAGE_ID_INVALID"
+        ));
+
+        let err = Error::from(rust_tdlib::errors::Error::Internal("receiver closed"));
+        assert!(matches!(err, Error::Client(_)));
+    }
+
+    #[test]
+    fn transient() {
+        let telegram = |code| Error::Telegram {
+            code,
+            message: String::new(),
+        };
+        assert!(telegram(429).is_transient());
+        assert!(telegram(502).is_transient());
+        assert!(!telegram(400).is_transient());
+        assert!(!Error::Config(String::new()).is_transient());
+
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        assert!(Error::from(busy).is_transient());
+        assert!(!Error::from(rusqlite::Error::QueryReturnedNoRows).is_transient());
+    }
+}
@@ -0,0 +1,9 @@
+//! The database side of the bot, split out of the binary so benches can use
+//! it. Everything talking to Telegram stays in the binary.
+
+#[macro_use]
+extern crate log;
+
+pub mod dates;
+pub mod db;
+pub mod plural;
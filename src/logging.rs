@@ -0,0 +1,141 @@
+//! `pretty_env_logger` behind a lock, so `/loglevel` can swap its filter
+//! without restarting
+
+use std::sync::RwLock;
+
+use env_logger::Logger;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct Reloadable(RwLock<Option<Logger>>);
+
+static LOGGER: Reloadable = Reloadable(RwLock::new(None));
+
+impl Log for Reloadable {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0
+            .read()
+            .expect("logger lock poisoned")
+            .as_ref()
+            .map_or(false, |logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = self.0.read().expect("logger lock poisoned").as_ref() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = self.0.read().expect("logger lock poisoned").as_ref() {
+            logger.flush();
+        }
+    }
+}
+
+/// Log with the filter in `RUST_LOG`
+pub fn init() {
+    let logger = pretty_env_logger::formatted_builder()
+        .parse_filters(&std::env::var("RUST_LOG").unwrap_or_default())
+        .build();
+
+    log::set_max_level(logger.filter());
+    *LOGGER.0.write().expect("logger lock poisoned") = Some(logger);
+    log::set_logger(&LOGGER).expect("logger already set");
+}
+
+/// Replace the filter with `directives`, in the same format as `RUST_LOG`
+pub fn set_filter(directives: &str) {
+    let logger = pretty_env_logger::formatted_builder()
+        .parse_filters(directives)
+        .build();
+
+    log::set_max_level(logger.filter());
+    *LOGGER.0.write().expect("logger lock poisoned") = Some(logger);
+}
+
+/// Check `directives` before they replace the filter. `env_logger` skips ones
+/// it can't parse and takes a mistyped level for a target, either way quietly
+/// turning off most logging.
+pub fn check_filter(directives: &str) -> Result<(), String> {
+    // Anything after a `/` is a regex on messages
+    let filters = directives.split_once('/').map_or(directives, |(f, _)| f);
+
+    let mut off = false;
+    for directive in filters.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = match directive.split_once('=') {
+            Some((target, _)) if !is_target(target) => {
+                return Err(format!("Invalid target `{target}` in `{directive}`"));
+            }
+            Some((_, level)) => level,
+            None if is_target(directive) && directive.parse::<LevelFilter>().is_err() => continue,
+            None => directive,
+        };
+
+        match level.parse::<LevelFilter>() {
+            Ok(LevelFilter::Off) => off = true,
+            Ok(_) => {}
+            Err(_) => {
+                return Err(format!(
+                    "Unknown level `{level}` in `{directive}`, expected off, error, warn, info, \
+                     debug or trace"
+                ))
+            }
+        }
+    }
+
+    let logger = pretty_env_logger::formatted_builder()
+        .parse_filters(directives)
+        .build();
+    let errors = Metadata::builder()
+        .level(Level::Error)
+        .target(env!("CARGO_PKG_NAME"))
+        .build();
+    if !off && !logger.enabled(&errors) {
+        return Err(format!(
+            "`{directives}` would hide even errors of the bot, e.g. add `{}=warn`, or `off` to \
+             turn logging off on purpose",
+            env!("CARGO_PKG_NAME")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether `target` looks like a module path
+fn is_target(target: &str) -> bool {
+    !target.is_empty()
+        && target
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_filters() {
+        for directives in [
+            "debug",
+            "realmkbot=debug",
+            "info,rust_tdlib=warn",
+            "realmkbot::db=trace,realmkbot=info",
+            "off",
+            "realmkbot=off",
+            "info/populat",
+        ] {
+            assert_eq!(check_filter(directives), Ok(()), "{directives}");
+        }
+    }
+
+    #[test]
+    fn invalid_filters() {
+        // Unknown levels
+        assert!(check_filter("realmkbot=verbose").is_err());
+        assert!(check_filter("info,realmkbot=debg").is_err());
+        // Taken for a target, leaving the bot silent
+        assert!(check_filter("debg").is_err());
+        assert!(check_filter("rust_tdlib=debug").is_err());
+        assert!(check_filter("real-mk=info").is_err());
+    }
+}
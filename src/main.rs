@@ -3,47 +3,282 @@
 #[macro_use]
 extern crate log;
 
-use std::{env, path::PathBuf, rc::Rc, sync::LazyLock};
+use std::{
+    cell::{Cell, LazyCell, RefCell},
+    collections::{HashMap, VecDeque},
+    env,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    rc::Rc,
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use color_eyre::{eyre::Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Locale;
+use chrono_tz::Tz;
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
 use redacted_debug::RedactedDebug;
 use rust_tdlib::{
     client::{tdlib_client::TdJson, Client},
     types::*,
 };
-use serde::Deserialize;
-use tap::Pipe;
-use tokio::{select, signal::ctrl_c};
+use serde::{Deserialize, Serialize};
+use tap::{Pipe, Tap};
+use tokio::{
+    runtime::{self, Runtime},
+    select,
+    signal::ctrl_c,
+    time::{sleep_until, Instant},
+};
+use tracing::{field::Empty, Instrument, Span};
+
+use realmkbot::{dates, db, plural};
 
 use crate::{
-    db::{MessageRecord, Messages},
+    dates::DateFormat,
+    db::{
+        Filter, MessageRecord, Messages, Rate, ResultId, SearchOrder, SearchResult, SelfForwards,
+        Serve, TextPolicy,
+    },
+    error::Error,
+    populate::{LiveWrites, PopulateSummary, Populator},
     tdlib::WorkerHandle,
 };
 
-mod db;
+mod cli;
+mod command;
+mod error;
+mod logging;
+mod populate;
 mod tdlib;
+mod telemetry;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     dotenvy::dotenv().ok();
     color_eyre::install().unwrap();
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "realmkbot=info");
     }
-    pretty_env_logger::init();
+    logging::init();
+
+    let mut args = env::args().skip(1);
+    if let Some(cmd) = args.next() {
+        return cli::run(&cmd, args);
+    }
+
+    build_runtime(Config::load())?.block_on(run())
+}
 
-    App::init().await?.load_chat_id().await?.run().await
+/// The bot itself always runs on the main thread, as `App` isn't `Send`. With
+/// the multi-thread flavor, TDLib's tasks run on worker threads instead, so
+/// they keep receiving updates while a slow SQLite query blocks the main
+/// thread.
+fn build_runtime(config: &Config) -> Result<Runtime> {
+    let mut builder = match config.runtime {
+        RuntimeFlavor::CurrentThread => runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            if let Some(threads) = config.worker_threads {
+                builder.worker_threads(threads);
+            }
+            builder
+        }
+    };
+
+    builder
+        .enable_all()
+        .build()
+        .wrap_err("Failed to build the runtime")
+}
+
+async fn run() -> Result<()> {
+    telemetry::init(Config::load())?;
+
+    let res = App::init()
+        .await?
+        .load_chat()
+        .await?
+        .prepared()?
+        .run()
+        .await;
+
+    telemetry::shutdown();
+    res
 }
 
+/// Quotes answered to an inline query
+const INLINE_RESULTS: u8 = 10;
+/// Most results Telegram accepts in a single inline answer
+const MAX_INLINE_RESULTS: usize = 50;
+// Leave room for the stats card, the repeat nudge and the browse link
+const _: () = assert!(INLINE_RESULTS as usize + 3 <= MAX_INLINE_RESULTS);
+/// Messages looked at by each reaction refresh unless configured otherwise,
+/// all fetched in one request
+const DEFAULT_REACTION_REFRESH_SAMPLE: usize = 50;
+const _: () = assert!(DEFAULT_REACTION_REFRESH_SAMPLE <= tdlib::MAX_GET_MESSAGES_BATCH);
+/// Users shown above and below the user on the stats card
+const RANK_CONTEXT: u8 = 2;
+
+/// Config keys that can be read from a file named by `<key>_file` instead
+const SECRETS: [&str; 3] = ["bot_token", "api_id", "api_hash"];
+
+/// Setting remembering `/pause` with `persist_pause`
+const PAUSED: &str = "paused";
+
+/// Setting remembering the [`ChatLink`] of the source chat, for the CLI
+const CHAT_LINK: &str = "chat_link";
+
 struct App<ID> {
     config: &'static Config,
     db: Rc<Messages>,
+    /// Read-only replica serving inline queries, if configured
+    read_db: Option<Rc<Messages>>,
     client: Client<TdJson>,
-    chat_id: ID,
+    chat: ID,
     handle: WorkerHandle,
+    /// Unix timestamp of when the bot started
+    started_at: i64,
+    /// The bot's own username, without the leading `@`
+    username: String,
+    /// Whether a repopulate is running or about to be started
+    populating: Cell<bool>,
+    /// Repopulate requested by an admin, picked up by the update loop
+    repopulate: Cell<Option<RepopulateRequest>>,
+    /// Messages written by updates during a repopulate, shared with it
+    live_writes: Rc<LiveWrites>,
+    /// Random quotes last shown to each user, newest last
+    recently_shown: RefCell<HashMap<i64, VecDeque<i64>>>,
+    /// Deletes of messages that were never stored, see `log_unknown_deletes`
+    unknown_deletes: Cell<u64>,
+    /// Inline queries are answered with `paused_text` only, see `/pause`
+    paused: Cell<bool>,
+    /// Sends answers and messages, retrying in the background
+    retrier: tdlib::Retrier,
+    /// Usernames of users looked up by id, see `App::user_label`
+    user_labels: RefCell<HashMap<i64, String>>,
+}
+
+/// Repopulate running alongside the update loop
+type Populating = Pin<Box<dyn Future<Output = Result<PopulateSummary>>>>;
+
+#[derive(Debug, Clone, Copy)]
+struct RepopulateRequest {
+    /// Where to report back to when done, `None` for the populate at startup
+    chat_id: Option<i64>,
+    force: bool,
+}
+
+/// The resolved source chat
+#[derive(Debug, Clone, Copy)]
+struct Chat {
+    id: i64,
+    source_type: SourceType,
+    link: ChatLink,
+}
+
+/// How messages of the source chat can be linked to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum ChatLink {
+    /// `t.me` links by username
+    Public,
+    /// `tg://` links by id, which only open for members
+    Private { supergroup_id: i64 },
+    /// Basic groups have no links to their messages
+    None,
+}
+
+impl ChatLink {
+    fn permalink(self, chat_name: &str, in_chat_id: i64) -> Option<String> {
+        match self {
+            ChatLink::Public => Some(format!("https://t.me/{chat_name}/{in_chat_id}")),
+            ChatLink::Private { supergroup_id } => Some(format!(
+                "tg://privatepost?channel={supergroup_id}&post={in_chat_id}"
+            )),
+            ChatLink::None => None,
+        }
+    }
+}
+
+impl Chat {
+    /// Build a record out of a raw message, resolving the author's name for
+    /// group sources and the name of whoever forwards came from. Names are
+    /// only looked up for messages that can be served.
+    async fn record(
+        self,
+        client: &Client<TdJson>,
+        config: &Config,
+        names: &Names,
+        msg: Message,
+        in_chat_id: i64,
+    ) -> Result<MessageRecord> {
+        let mut record = MessageRecord::from_raw(msg, in_chat_id, config.text_policy())?;
+        if !record.servable {
+            return Ok(record);
+        }
+
+        if let Some(origin) = record
+            .forward_origin
+            .filter(|_| record.forward_name.is_none())
+        {
+            record.forward_name = names.get(client, origin).await;
+        }
+
+        if let (SourceType::Group, Some(user_id)) = (self.source_type, record.author_id) {
+            record.author_name = names.get(client, user_id).await;
+        }
+
+        Ok(record)
+    }
+}
+
+/// Names of users and titles of chats by id, so each is only asked for once
+/// e.g. while populating
+#[derive(Debug, Default)]
+pub struct Names(RefCell<HashMap<i64, Option<String>>>);
+
+impl Names {
+    /// Name of a user, or title of a chat, by id. Users have positive ids and
+    /// chats negative ones. `None` if TDLib doesn't know them.
+    pub async fn get(&self, client: &Client<TdJson>, id: i64) -> Option<String> {
+        if let Some(name) = self.0.borrow().get(&id) {
+            return name.clone();
+        }
+
+        let name = if id > 0 {
+            GetUser::builder()
+                .user_id(id)
+                .build()
+                .pipe(|s| client.get_user(s))
+                .await
+                .map(|user| match user.last_name().as_str() {
+                    "" => user.first_name().to_owned(),
+                    last => format!("{} {last}", user.first_name()),
+                })
+        } else {
+            GetChat::builder()
+                .chat_id(id)
+                .build()
+                .pipe(|s| client.get_chat(s))
+                .await
+                .map(|chat| chat.title().to_owned())
+        };
+
+        let name = name
+            .map_err(|e| debug!("Failed to get name of {id}: {e:?}"))
+            .ok();
+        self.0.borrow_mut().insert(id, name.clone());
+
+        name
+    }
 }
 
 impl App<()> {
+    #[tracing::instrument(skip_all)]
     async fn init() -> Result<Self> {
         let config = Config::load();
 
@@ -52,40 +287,232 @@ impl App<()> {
 
         tokio::fs::create_dir_all(&config.data_dir).await?;
 
-        let db = Messages::open(config.data_dir.join("main.db"))?.pipe(Rc::new);
-        let (client, handle) = tdlib::init(config)
-            .await
-            .wrap_err("Failed to initialize TDLib")?;
-        let this = Self {
+        let db_path = config.db_path();
+        if let Some(parent) = db_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .wrap_err_with(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        let db = if config.read_only {
+            info!("Read-only instance, leaving writes to the writer");
+            Messages::open_readonly(db_path)?
+        } else {
+            Messages::open(db_path)?
+        }
+        .pipe(Rc::new);
+        info!("Database schema version {}", db.schema_version()?);
+        let read_db = config.read_db_path.as_ref().and_then(|path| {
+            Messages::open_readonly(path)
+                .map(Rc::new)
+                .map_err(|e| warn!("Failed to open replica, reading from the primary: {e:#}"))
+                .ok()
+        });
+        let paused = config.persist_pause && db.setting(PAUSED)?.as_deref() == Some("true");
+        if paused {
+            warn!("Serving is paused, send /resume to serve again");
+        }
+
+        tdlib::check_session(config).await?;
+        let (client, handle) = Self::init_tdlib(config).await?;
+        let me = client.get_me(GetMe::builder().build()).await?;
+        tdlib::record_session(config, me.id()).await?;
+        let username = me.username().to_owned();
+
+        info!("Logged in as @{username}");
+
+        let retrier = tdlib::Retrier::new(client.clone(), config.send_retries);
+        Ok(Self {
             config,
             db,
+            read_db,
             client,
-            chat_id: (),
+            chat: (),
             handle,
-        };
-        this.client
-            .get_me(GetMe::builder().build())
-            .await?
-            .first_name()
-            .pipe(|x| info!("Logged in as @{x}"));
-        this.populate().await?;
-        Ok(this)
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or_default(),
+            username,
+            populating: Cell::new(false),
+            repopulate: Cell::new(None),
+            live_writes: Rc::default(),
+            recently_shown: RefCell::default(),
+            unknown_deletes: Cell::default(),
+            paused: Cell::new(paused),
+            retrier,
+            user_labels: RefCell::default(),
+        })
+    }
+
+    /// Initialize TDLib, retrying with exponential backoff on failure so a network
+    /// that's not ready yet at boot doesn't bring the bot down
+    async fn init_tdlib(config: &Config) -> Result<(Client<TdJson>, WorkerHandle)> {
+        let mut backoff = Duration::from_millis(config.init_backoff_ms);
+
+        for attempt in 1.. {
+            match tdlib::init(config).await {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt < config.init_attempts => {
+                    warn!(
+                        "Failed to initialize TDLib (attempt {attempt}/{}), retrying in \
+                         {backoff:?}: {e:#}",
+                        config.init_attempts
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e).wrap_err("Failed to initialize TDLib"),
+            }
+        }
+
+        unreachable!()
     }
 }
 
-impl App<i64> {
+impl App<Chat> {
+    /// Get ready to serve. Populating is only started here and runs in the
+    /// update loop, so inline queries are answered meanwhile.
+    fn prepared(self) -> Result<Self> {
+        if self.config.per_chat_stats && !self.config.read_only {
+            let moved = self.db.scope_counts(self.chat.id)?;
+            if moved > 0 {
+                info!(
+                    "Moved counts of {moved} user(s) over to chat {}",
+                    self.chat.id
+                );
+            }
+        }
+        if self.config.skip_populate || self.config.read_only {
+            info!("Skipped populating");
+            self.check_forwarded()?;
+        } else {
+            self.populating.set(true);
+            self.repopulate.set(Some(RepopulateRequest {
+                chat_id: None,
+                force: false,
+            }));
+        }
+        if self.config.warmup {
+            self.warm_up()?;
+        }
+        Ok(self)
+    }
+
+    /// Serving only forwarded messages from a chat without any is the most
+    /// common reason for nothing showing up, so call it out
+    fn check_forwarded(&self) -> Result<()> {
+        if self.filter().serve != Serve::Forwarded {
+            return Ok(());
+        }
+
+        let forwarded = Filter {
+            serve: Serve::Forwarded,
+            ..Filter::default()
+        }
+        .pipe(|filter| self.db.count(&filter))?;
+
+        if forwarded == 0 {
+            warn!(
+                "No forwarded messages stored, but only forwarded messages are served, so inline \
+                 queries will come up empty. Set `serve` to `all` or `original` to serve messages \
+                 posted directly in the chat."
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Run a few throwaway queries so the first user after a restart doesn't
+    /// hit a cold cache
+    fn warm_up(&self) -> Result<()> {
+        info!("Warming up");
+
+        let start = Instant::now();
+        let filter = self.filter();
+
+        let db = self.reader();
+        db.random(INLINE_RESULTS, &filter)?;
+        db.search("e", INLINE_RESULTS, &filter, self.config.search_order)?;
+        db.count(&filter)?;
+
+        info!("Warmed up in {:?}", start.elapsed());
+
+        Ok(())
+    }
+
     async fn run(&mut self) -> Result<()> {
         info!("Running");
 
+        let debounce = Duration::from_millis(self.config.inline_debounce_ms);
+        // Latest inline query of each user that hasn't been answered yet
+        let mut pending: HashMap<i64, (Instant, UpdateNewInlineQuery)> = HashMap::new();
+        // Repopulate running in the background, with the chat to report back to
+        let mut repopulating: Option<(Option<i64>, Populating)> = None;
+        // First tick is immediate, so retention is applied on startup too
+        let mut prune = tokio::time::interval(Duration::from_secs(self.config.prune_interval_secs));
+        // Unlike pruning, nothing to catch up on at startup
+        let refresh_every = Duration::from_secs(self.config.reaction_refresh_secs.unwrap_or(1));
+        let mut refresh = tokio::time::interval_at(Instant::now() + refresh_every, refresh_every);
+
         loop {
+            if let Some(req) = self.repopulate.take() {
+                repopulating = Some((req.chat_id, Box::pin(self.populator(req.force).run())));
+            }
+
+            let deadline = pending.values().map(|(at, _)| *at).min();
+
             select! {
                 update = self.handle.next_update() => {
-                    if let Some(update) = update {
-                        if let Err(e) = self.handle_update(update).await {
+                    let Some(update) = update else { break };
+
+                    match *update {
+                        Update::NewInlineQuery(query) if !debounce.is_zero() => {
+                            let user = query.sender_user_id();
+                            if pending.insert(user, (Instant::now() + debounce, query)).is_some() {
+                                debug!("Inline query from {user} superseded");
+                            }
+                        }
+                        update => {
+                            if let Err(e) = self.handle_update_resolving(&update).await {
+                                warn!("{e:#?}")
+                            }
+                        }
+                    }
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    let now = Instant::now();
+                    let due = pending
+                        .iter()
+                        .filter(|(_, (at, _))| *at <= now)
+                        .map(|(user, _)| *user)
+                        .collect::<Vec<_>>();
+
+                    for user in due {
+                        let Some((_, query)) = pending.remove(&user) else { continue };
+                        if let Err(e) = self.handle_update(&Update::NewInlineQuery(query)).await {
                             warn!("{e:#?}")
                         }
-                    } else {
-                        break
+                    }
+                },
+                res = async {
+                    match repopulating.as_mut() {
+                        Some((_, populate)) => populate.await,
+                        None => std::future::pending().await,
+                    }
+                }, if repopulating.is_some() => {
+                    let Some((chat_id, _)) = repopulating.take() else { continue };
+                    self.repopulated(chat_id, res).await;
+                },
+                _ = prune.tick(), if self.config.retention_days.is_some() && !self.config.read_only => {
+                    if let Err(e) = self.prune() {
+                        warn!("{e:#?}")
+                    }
+                },
+                _ = refresh.tick(), if self.config.reaction_refresh_secs.is_some() && !self.config.read_only => {
+                    match self.refresh_reactions().await {
+                        Ok(num) => debug!("Reactions of {num} message(s) refreshed"),
+                        Err(e) => warn!("Failed to refresh reactions: {e:#?}"),
                     }
                 },
                 _ = ctrl_c() => { break }
@@ -93,17 +520,204 @@ impl App<i64> {
         }
 
         info!("Shutting down");
+        self.drain(pending, repopulating).await;
+
+        Ok(())
+    }
+
+    /// Remove messages older than `retention_days`
+    fn prune(&self) -> Result<()> {
+        let Some(days) = self.config.retention_days else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let num = self.db.prune_before(now - days as i64 * 24 * 60 * 60)?;
+
+        info!("{num} message(s) older than {days} day(s) pruned");
+
         Ok(())
     }
 
-    async fn handle_update(&self, update: Box<Update>) -> Result<()> {
-        match *update {
+    /// Get a sample of stored messages again for their reactions, as updates
+    /// only come in for messages TDLib is following
+    async fn refresh_reactions(&self) -> Result<usize> {
+        let ids = self
+            .db
+            .reaction_sample(self.config.reaction_refresh_sample)?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let request = GetMessages::builder()
+            .chat_id(self.chat.id)
+            .message_ids(ids)
+            .build();
+        let messages = self.client.get_messages(&request).await?;
+
+        let mut num = 0;
+        for msg in messages.messages().iter().flatten() {
+            let reactions = serde_json::to_value(msg.interaction_info())?
+                .pipe(|info| db::reaction_count(&info));
+            self.live_writes.touch([msg.id()]);
+            num += self.db.set_reactions(msg.id(), reactions)?;
+        }
+
+        Ok(num)
+    }
+
+    /// Finish work the update loop was still holding on to: answer debounced
+    /// inline queries and let a running repopulate complete, giving up after
+    /// `shutdown_grace_ms`. Updates are no longer received by now.
+    async fn drain(
+        &self,
+        pending: HashMap<i64, (Instant, UpdateNewInlineQuery)>,
+        repopulating: Option<(Option<i64>, Populating)>,
+    ) {
+        let total = pending.len() + usize::from(repopulating.is_some());
+        if total == 0 {
+            return;
+        }
+
+        info!("Draining {total} outstanding task(s)");
+
+        let mut drained = 0;
+        let work = async {
+            for (_, (_, query)) in pending {
+                if let Err(e) = self.handle_update(&Update::NewInlineQuery(query)).await {
+                    warn!("{e:#?}")
+                }
+                drained += 1;
+            }
+
+            if let Some((chat_id, populate)) = repopulating {
+                let res = populate.await;
+                self.repopulated(chat_id, res).await;
+                drained += 1;
+            }
+        };
+
+        let grace = Duration::from_millis(self.config.shutdown_grace_ms);
+        if tokio::time::timeout(grace, work).await.is_err() {
+            warn!("Shutdown grace period of {grace:?} exceeded");
+        }
+
+        info!("Drained {drained} task(s), cancelled {}", total - drained);
+    }
+
+    /// Report the outcome of a repopulate to the admin who asked for it, or
+    /// just log it for the populate at startup
+    async fn repopulated(&self, chat_id: Option<i64>, res: Result<PopulateSummary>) {
+        self.populating.set(false);
+
+        let Some(chat_id) = chat_id else {
+            match res {
+                Ok(_) => {
+                    if let Err(e) = self.check_forwarded() {
+                        warn!("{e:#?}")
+                    }
+                }
+                Err(e) => warn!("Failed to populate: {e:#?}"),
+            }
+            return;
+        };
+
+        let reply = match res {
+            Ok(summary) => format!("Repopulated, {summary}"),
+            Err(e) => {
+                warn!("Failed to repopulate: {e:#?}");
+                format!("Failed to repopulate: {e}")
+            }
+        };
+        if let Err(e) = self.send_text(chat_id, reply).await {
+            warn!("{e:#?}")
+        }
+    }
+
+    /// Handle an update, resolving the chat again and retrying once if TDLib
+    /// reports the cached chat as invalid, e.g. after it was migrated
+    async fn handle_update_resolving(&mut self, update: &Update) -> Result<()> {
+        match self.handle_update(update).await {
+            Err(e) if tdlib::is_chat_invalid(&e) => {
+                warn!(
+                    "Chat {} is no longer valid, resolving again: {e:#}",
+                    self.chat.id
+                );
+
+                let chat = self.resolve_chat().await?;
+                if chat.id != self.chat.id {
+                    info!("Chat id changed: {} -> {}", self.chat.id, chat.id);
+                }
+                self.chat = chat;
+
+                self.handle_update(update).await
+            }
+            res => res,
+        }
+    }
+
+    async fn handle_update(&self, update: &Update) -> Result<()> {
+        let kind = match update {
+            Update::DeleteMessages(_) => "DeleteMessages",
+            Update::NewInlineQuery(_) => "NewInlineQuery",
+            Update::NewMessage(_) => "NewMessage",
+            Update::NewChosenInlineResult(_) => "NewChosenInlineResult",
+            Update::MessageInteractionInfo(_) => "MessageInteractionInfo",
+            Update::NewInlineCallbackQuery(_) => "NewInlineCallbackQuery",
+            _ => "Other",
+        };
+
+        // Filled in by the branches that know them
+        let span = tracing::info_span!(
+            "handle_update",
+            kind,
+            chat_id = self.chat.id,
+            user_id = Empty,
+            query_len = Empty,
+            result_count = Empty,
+        );
+
+        let start = Instant::now();
+        let res = self.dispatch_update(update).instrument(span).await;
+        let elapsed = start.elapsed();
+
+        // Not slept out, so not counted by `tdlib::flood_waits`
+        if let Some(secs) = res.as_ref().err().and_then(tdlib::flood_wait) {
+            warn!("Flood wait of {secs}s handling {kind}");
+        }
+
+        if elapsed > Duration::from_millis(self.config.slow_update_ms) {
+            warn!("Handling {kind} took {elapsed:?}");
+        } else {
+            debug!("Handling {kind} took {elapsed:?}");
+        }
+
+        res
+    }
+
+    async fn dispatch_update(&self, update: &Update) -> Result<()> {
+        // Only the writer follows the source chat and counts sends
+        if self.config.read_only
+            && !matches!(update, Update::NewInlineQuery(_) | Update::NewMessage(_))
+        {
+            return Ok(());
+        }
+
+        match update {
+            Update::NewMessage(msg)
+                if self.config.read_only && !command::is_private(msg.message()) =>
+            {
+                debug!("Read-only, skip message from the source chat")
+            }
             Update::DeleteMessages(update) => {
-                if update.chat_id() != self.chat_id {
+                if update.chat_id() != self.chat.id {
                     debug!(
                         "Unknown channel, skip ({} != {})",
                         update.chat_id(),
-                        self.chat_id
+                        self.chat.id
                     );
 
                     return Result::<()>::Ok(());
@@ -111,47 +725,75 @@ impl App<i64> {
 
                 debug!("{update:?}");
 
-                self.db
-                    .delete(update.message_ids())?
-                    .pipe(|num| info!("{num} message(s) deleted"));
+                if self.config.log_unknown_deletes {
+                    self.log_unknown_deletes(update.message_ids())?;
+                }
+
+                self.live_writes.touch(update.message_ids().iter().copied());
+                match self.config.delete_mode {
+                    DeleteMode::Soft => self.db.delete(update.message_ids())?,
+                    DeleteMode::Hard => self.db.purge(update.message_ids())?,
+                }
+                .pipe(|num| info!("{num} message(s) deleted"));
             }
-            Update::NewInlineQuery(query) => {
-                info!("New query from {}", query.sender_user_id());
-                debug!("{query:?}");
+            Update::NewInlineQuery(query) => self.answer_inline_query(query).await?,
+            Update::NewChosenInlineResult(result) => {
+                let user_id = result.sender_user_id();
+                Span::current().record("user_id", user_id);
 
-                let results = if query.query().is_empty() {
-                    self.db.random(10)?
-                } else {
-                    self.db.search(query.query(), 10)?
+                let in_chat_id = match result.result_id().parse() {
+                    Ok(ResultId::Message(in_chat_id)) => in_chat_id,
+                    Ok(ResultId::Report(in_chat_id)) if self.config.reports => {
+                        if self.db.report(in_chat_id, user_id)? {
+                            info!("Quote {in_chat_id} reported by {user_id}");
+                        }
+                        return Ok(());
+                    }
+                    _ => return Ok(()),
+                };
+
+                if self.is_blocked(user_id)? {
+                    debug!("Send from blocked user {user_id}, not counted");
+                    return Ok(());
                 }
-                .into_iter()
-                .map(InputInlineQueryResult::from)
-                .collect();
-
-                AnswerInlineQuery::builder()
-                    .inline_query_id(query.id())
-                    .cache_time(0)
-                    .results(results)
-                    .build()
-                    .pipe(|a| self.client.answer_inline_query(a))
-                    .await?;
+
+                if self.is_repeating(user_id, in_chat_id)? {
+                    debug!("Repeated send of {in_chat_id} from {user_id}, not counted");
+                    return Ok(());
+                }
+
+                let dedup_secs = self.config.send_dedup_secs;
+                if !self.db.record_send(user_id, in_chat_id, dedup_secs)? {
+                    debug!("Duplicated send of {in_chat_id} from {user_id}, skip");
+                    return Ok(());
+                }
+
+                self.db.bump_user_count(user_id, self.count_chat())?;
+            }
+            Update::NewMessage(msg) if command::is_private(msg.message()) => {
+                self.handle_private_message(msg.message()).await?
             }
             Update::NewMessage(msg) => {
-                if msg.message().chat_id() != self.chat_id {
+                if msg.message().chat_id() != self.chat.id {
                     debug!(
                         "Unknown channel, skip ({} != {})",
                         msg.message().chat_id(),
-                        self.chat_id
+                        self.chat.id
                     );
 
                     return Result::<()>::Ok(());
                 }
 
+                if !self.config.catch_up && i64::from(msg.message().date()) < self.started_at {
+                    debug!("Message sent before startup, skip");
+                    return Ok(());
+                }
+
                 info!("New message in channel");
                 debug!("{msg:?}");
 
                 let link = GetMessageLink::builder()
-                    .chat_id(self.chat_id)
+                    .chat_id(self.chat.id)
                     .message_id(msg.message().id())
                     .build()
                     .pipe(|r| self.client.get_message_link(r))
@@ -160,8 +802,40 @@ impl App<i64> {
                 let Some(in_chat_id) = link.link().split('/').last().and_then(|x| x.parse().ok())
                 else { return Ok(()); };
 
-                let msg = MessageRecord::from_raw(msg.message().to_owned(), in_chat_id)?;
+                let msg = self
+                    .chat
+                    .record(
+                        &self.client,
+                        self.config,
+                        &Names::default(),
+                        msg.message().to_owned(),
+                        in_chat_id,
+                    )
+                    .await?;
+                self.live_writes.touch([msg.id]);
                 self.db.insert_one(&msg)?;
+
+                if let Some(max_rows) = self.config.max_db_rows {
+                    self.db.evict_over(max_rows)?;
+                }
+            }
+            Update::MessageInteractionInfo(update) => {
+                if update.chat_id() != self.chat.id {
+                    return Ok(());
+                }
+
+                let reactions = serde_json::to_value(update.interaction_info())?
+                    .pipe(|info| db::reaction_count(&info));
+                self.live_writes.touch([update.message_id()]);
+                self.db.set_reactions(update.message_id(), reactions)?;
+                debug!(
+                    "Message {} has {reactions} reaction(s)",
+                    update.message_id()
+                );
+            }
+            Update::NewInlineCallbackQuery(query) => {
+                Span::current().record("user_id", query.sender_user_id());
+                self.handle_callback(query).await?
             }
             u => {
                 debug!("{u:?}")
@@ -169,101 +843,1132 @@ impl App<i64> {
         }
         Ok(())
     }
-}
 
-impl<ID> App<ID> {
-    async fn load_chat_id(self) -> Result<App<i64>> {
-        let chat_id = GetMessageLinkInfo::builder()
-            .url(format!(
-                "tg:resolve?domain={}&post=1",
-                self.config.chat_name
-            ))
-            .build()
-            .pipe(|s| self.client.get_message_link_info(s))
-            .await?
-            .chat_id();
+    async fn answer_inline_query(&self, query: &UpdateNewInlineQuery) -> Result<()> {
+        info!("New query from {}", query.sender_user_id());
+        debug!("{query:?}");
 
-        Ok(App {
-            chat_id,
-            config: self.config,
-            db: self.db,
-            client: self.client,
-            handle: self.handle,
-        })
-    }
+        Span::current()
+            .record("user_id", query.sender_user_id())
+            .record("query_len", query.query().chars().count());
 
-    async fn populate(&self) -> Result<()> {
-        if self.config.skip_populate {
-            info!("Skipped populating");
-            return Ok(());
+        if self.paused.get() {
+            debug!("Paused, answering with `paused_text`");
+            let answer = AnswerInlineQuery::builder()
+                .inline_query_id(query.id())
+                .is_personal(false)
+                .cache_time(0)
+                .results(vec![db::loading_result(&self.config.paused_text)])
+                .build();
+
+            return self
+                .retrier
+                .send(answer, |client, answer| async move {
+                    client.answer_inline_query(answer).await
+                })
+                .await
+                .wrap_err("Failed to answer inline query");
         }
 
-        info!("Populating");
+        let filter = self.filter();
+        // Picking the replica takes queries of its own, only worth it when the
+        // answer reads quotes
+        let db = LazyCell::new(|| self.reader());
+        let mut results = Vec::<InputInlineQueryResult>::new();
 
-        let mut consecutive_empty_msg = 0;
-        let mut added = 0;
+        // Results depending on who's asking must not be served to other users
+        let mut is_personal = false;
 
-        for id in 1.. {
-            if consecutive_empty_msg > 10 {
-                break;
+        if let Some(in_chat_id) = self.report_query(query.query())? {
+            results.push(db::report_result(in_chat_id));
+        } else if let Some(author) = query.query().strip_prefix('@') {
+            // `@author` browses quotes by someone
+            let author = author.trim();
+            if self.is_too_short(author) {
+                results.push(db::loading_result(&self.config.short_query_text));
+            } else {
+                let quotes = db.by_author(author, INLINE_RESULTS, &filter)?;
+                if quotes.is_empty() && !author.is_empty() {
+                    results.push(db::loading_result(&format!("No quotes by {author} yet")));
+                }
+                results.extend(quotes.into_iter().map(|quote| self.inline_result(quote)));
             }
-
-            if self.db.exists(id)? {
-                consecutive_empty_msg = 0;
-                continue;
+        } else if let Some(topic) = query.query().strip_prefix('?') {
+            // `?topic` picks a single random quote about the topic
+            if self.is_too_short(topic) {
+                results.push(db::loading_result(&self.config.short_query_text));
+            } else {
+                db.search(topic.trim(), 1, &filter, SearchOrder::Random)?
+                    .into_iter()
+                    .map(|quote| self.inline_result(quote))
+                    .pipe(|x| results.extend(x));
+            }
+        } else if query.query().is_empty() {
+            let user_id = query.sender_user_id();
+            let mut stats = match self.config.stats_position {
+                StatsPosition::Hidden => None,
+                _ => self.db.user_stats(user_id, self.stats_chat())?,
+            };
+            if let Some(stats) = &mut stats {
+                stats.nearby =
+                    self.db
+                        .user_rank_context(user_id, self.stats_chat(), RANK_CONTEXT)?;
             }
+            is_personal |= stats.is_some();
 
-            debug!("Getting {id}");
-            let res = GetMessageLinkInfo::builder()
-                .url(format!(
-                    "tg:resolve?domain={}&post={}",
-                    self.config.chat_name, id
-                ))
-                .build()
-                .pipe(|s| self.client.get_message_link_info(s))
-                .await?
-                .message()
-                .to_owned();
+            let mut recent = self
+                .recently_shown
+                .borrow()
+                .get(&user_id)
+                .map(|shown| shown.iter().copied().collect::<Vec<_>>())
+                .unwrap_or_default();
+            is_personal |= !recent.is_empty();
 
-            let Some(msg) = res else {
-                consecutive_empty_msg += 1;
-                continue;
+            // Offer the quote being repeated last, after a nudge to pick another
+            if let Some((in_chat_id, times)) = self.repeat_streak(user_id)? {
+                results.push(db::nudge_result(in_chat_id, times));
+                recent.push(in_chat_id);
+                is_personal = true;
+            }
+
+            let quotes = match self.config.empty_query_mode {
+                EmptyQueryMode::Random => {
+                    let quotes = db.random(INLINE_RESULTS, &Filter { recent, ..filter })?;
+                    self.remember_shown(user_id, quotes.iter().map(|quote| quote.in_chat_id));
+                    quotes
+                }
+                EmptyQueryMode::Recent => db.recent(INLINE_RESULTS, &filter)?,
+                EmptyQueryMode::None => vec![],
             };
+            let quotes = quotes.into_iter().map(|quote| self.inline_result(quote));
 
-            consecutive_empty_msg = 0;
-            MessageRecord::from_raw(msg, id)?.pipe(|msg| self.db.insert_one(&msg))?;
-            added += 1;
-            debug!("Added");
+            match (stats, self.config.stats_position) {
+                (Some(stats), StatsPosition::Top) => {
+                    results.push(
+                        stats.into_inline_result(&self.config.quote_forms, &self.config.locale),
+                    );
+                    results.extend(quotes);
+                }
+                (Some(stats), StatsPosition::Bottom) => {
+                    results.extend(quotes);
+                    results.push(
+                        stats.into_inline_result(&self.config.quote_forms, &self.config.locale),
+                    );
+                }
+                _ => results.extend(quotes),
+            }
+        } else if self.is_too_short(query.query()) {
+            results.push(db::loading_result(&self.config.short_query_text));
+        } else {
+            db.search(
+                query.query(),
+                INLINE_RESULTS,
+                &filter,
+                self.config.search_order,
+            )?
+            .into_iter()
+            .map(|quote| self.inline_result(quote))
+            .pipe(|x| results.extend(x));
         }
 
-        info!("Done, {added} message(s) added");
+        // Nothing found may just mean nothing is stored yet
+        if results.is_empty() && (self.populating.get() || db.is_empty()?) {
+            results.push(db::loading_result(&self.config.loading_text));
+        }
 
-        Ok(())
-    }
-}
+        if let Some(url) = &self.config.browse_all_url {
+            if !results.is_empty() {
+                results.push(db::browse_result(url, &self.config.browse_all_text));
+            }
+        }
 
-#[derive(RedactedDebug, Deserialize)]
-pub struct Config {
-    #[redacted]
-    pub bot_token: String,
-    pub chat_name: String,
-    #[redacted]
-    pub api_id: i32,
-    #[redacted]
-    pub api_hash: String,
+        Span::current().record("result_count", results.len());
 
-    #[serde(default = "default_data_dir")]
-    pub data_dir: PathBuf,
+        let served = results
+            .iter()
+            .filter_map(|result| match db::result_id(result)? {
+                ResultId::Message(in_chat_id) => Some(in_chat_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Only eviction looks at when quotes were last served
+        if !self.config.read_only && self.config.max_db_rows.is_some() {
+            self.db.mark_served(&served)?;
+        }
 
-    #[serde(default)]
-    pub skip_populate: bool,
-}
+        // Media quotes to refresh if Telegram rejects their file ids
+        let media = results
+            .iter()
+            .filter(|result| {
+                matches!(
+                    result,
+                    InputInlineQueryResult::Photo(_)
+                        | InputInlineQueryResult::Sticker(_)
+                        | InputInlineQueryResult::Animation(_)
+                )
+            })
+            .filter_map(|result| match db::result_id(result)? {
+                ResultId::Message(in_chat_id) => Some(in_chat_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
 
-fn default_data_dir() -> PathBuf {
-    dirs::data_dir()
-        .expect("data dir cannot be found")
-        .join("realmkbot")
-}
+        // Only offered to users new to the bot, so the answer is theirs alone
+        let switch_pm = match &self.config.switch_pm_text {
+            Some(text) if self.is_first_visit(query.sender_user_id())? => Some(text),
+            _ => None,
+        };
+        is_personal |= switch_pm.is_some();
+
+        let mut answer = AnswerInlineQuery::builder();
+        answer
+            .inline_query_id(query.id())
+            .is_personal(is_personal)
+            .cache_time(0)
+            .results(results);
+
+        if let Some(text) = switch_pm {
+            answer
+                .switch_pm_text(text)
+                .switch_pm_parameter(&self.config.switch_pm_parameter);
+        }
+
+        let answer = answer.build();
+        let res = if switch_pm.is_some() {
+            // Retries in the background could still fail after the user is
+            // marked welcomed, so the button is sent once and offered again
+            // on the next query if that doesn't work out
+            self.client
+                .answer_inline_query(answer)
+                .await
+                .map(drop)
+                .map_err(|e| Error::from(e).into())
+        } else {
+            self.retrier
+                .send(answer, |client, answer| async move {
+                    client.answer_inline_query(answer).await
+                })
+                .await
+        };
+
+        match res {
+            Ok(()) if switch_pm.is_some() && !self.config.read_only => {
+                self.db.welcome(query.sender_user_id()).map(drop)
+            }
+            Ok(()) => Ok(()),
+            // Nobody is waiting for the answer anymore
+            Err(e) if tdlib::is_query_expired(&e) => {
+                info!("Inline query {} expired before it was answered", query.id());
+                Ok(())
+            }
+            // Clients query again as the user types, by then with fresh ids
+            Err(e) if tdlib::is_file_invalid(&e) && !media.is_empty() => {
+                warn!(
+                    "File ids rejected ({e}), refreshing {} media quote(s)",
+                    media.len()
+                );
+                self.refresh_media(&media).await
+            }
+            Err(e) => Err(e).wrap_err("Failed to answer inline query"),
+        }
+    }
+}
+
+impl<ID> App<ID> {
+    #[tracing::instrument(skip_all)]
+    async fn load_chat(self) -> Result<App<Chat>> {
+        let chat = self.resolve_chat().await?;
+
+        Ok(App {
+            chat,
+            config: self.config,
+            db: self.db,
+            read_db: self.read_db,
+            client: self.client,
+            handle: self.handle,
+            started_at: self.started_at,
+            username: self.username,
+            populating: self.populating,
+            repopulate: self.repopulate,
+            live_writes: self.live_writes,
+            recently_shown: self.recently_shown,
+            unknown_deletes: self.unknown_deletes,
+            paused: self.paused,
+            retrier: self.retrier,
+            user_labels: self.user_labels,
+        })
+    }
+
+    async fn resolve_chat(&self) -> Result<Chat> {
+        let id = GetMessageLinkInfo::builder()
+            .url(format!(
+                "tg:resolve?domain={}&post=1",
+                self.config.chat_name
+            ))
+            .build()
+            .pipe(|s| self.client.get_message_link_info(s))
+            .await?
+            .chat_id();
+
+        let chat_type = GetChat::builder()
+            .chat_id(id)
+            .build()
+            .pipe(|s| self.client.get_chat(s))
+            .await?
+            .type_()
+            .clone();
+        let detected = match &chat_type {
+            ChatType::Supergroup(group) if group.is_channel() => SourceType::Channel,
+            _ => SourceType::Group,
+        };
+
+        let link = match &chat_type {
+            ChatType::Supergroup(group) => {
+                let supergroup = GetSupergroup::builder()
+                    .supergroup_id(group.supergroup_id())
+                    .build()
+                    .pipe(|s| self.client.get_supergroup(s))
+                    .await?;
+                if supergroup.username().is_empty() {
+                    ChatLink::Private {
+                        supergroup_id: group.supergroup_id(),
+                    }
+                } else {
+                    ChatLink::Public
+                }
+            }
+            _ => ChatLink::None,
+        };
+        if !self.config.read_only {
+            self.db
+                .set_setting(CHAT_LINK, &serde_json::to_string(&link)?)?;
+        }
+
+        let source_type = match self.config.source_type {
+            Some(hint) if hint != detected => {
+                warn!("Chat detected as {detected:?} but configured as {hint:?}, using {hint:?}");
+                hint
+            }
+            Some(hint) => hint,
+            None => detected,
+        };
+
+        info!("Source chat {id} is a {source_type:?}, linked to as {link:?}");
+
+        Ok(Chat {
+            id,
+            source_type,
+            link,
+        })
+    }
+}
+
+impl App<Chat> {
+    /// Whether a search term is shorter than `min_query_len`, so it would match
+    /// about everything, and slowly
+    fn is_too_short(&self, term: &str) -> bool {
+        term.trim().chars().count() < self.config.min_query_len
+    }
+
+    fn populator(&self, force: bool) -> Populator {
+        Populator {
+            config: self.config,
+            db: self.db.clone(),
+            client: self.client.clone(),
+            chat: self.chat,
+            force,
+            live_writes: self.live_writes.clone(),
+            names: Names::default(),
+        }
+    }
+
+    fn inline_result(&self, mut quote: SearchResult) -> InputInlineQueryResult {
+        let permalink = self
+            .chat
+            .link
+            .permalink(&self.config.chat_name, quote.in_chat_id);
+        let footer = self
+            .config
+            .footer
+            .replace("{bot}", &self.username)
+            .replace("{link}", permalink.as_deref().unwrap_or_default());
+
+        // Presses go to the bot that sent the message, which must be able to
+        // write them down
+        let buttons = (self.config.ratings && !self.config.read_only)
+            .then(|| db::rating_buttons(quote.in_chat_id));
+
+        let dates = self.config.date_format();
+        // Telegram refuses results without a title
+        quote.title = Some(quote.render(&self.config.title_template, &dates))
+            .filter(|title| !title.trim().is_empty());
+        quote.description = Some(quote.render(&self.config.description_template, &dates));
+        quote.permalink = permalink;
+        quote.icon_url = self.config.article_thumbnail_url.clone();
+        quote.into_inline_result(&footer, buttons.as_ref())
+    }
+
+    /// Buttons pressed under messages sent through the bot, only rating
+    /// buttons for now
+    async fn handle_callback(&self, query: &UpdateNewInlineCallbackQuery) -> Result<()> {
+        let CallbackQueryPayload::Data(payload) = query.payload() else {
+            return Ok(());
+        };
+        let Some(rate) = BASE64
+            .decode(payload.data())
+            .ok()
+            .and_then(|data| String::from_utf8(data).ok())
+            .and_then(|data| data.parse::<Rate>().ok())
+        else {
+            debug!("Unknown callback data {:?}", payload.data());
+            return Ok(());
+        };
+
+        let text = if !self.config.ratings {
+            "Ratings are turned off".to_owned()
+        } else if self.is_blocked(query.sender_user_id())? {
+            "You can't rate quotes".to_owned()
+        } else {
+            let total = self
+                .db
+                .rate(rate.in_chat_id, query.sender_user_id(), rate.score)?;
+            debug!(
+                "Quote {} rated {} by {}, {total} in total",
+                rate.in_chat_id,
+                rate.score,
+                query.sender_user_id()
+            );
+
+            format!("Thanks! Quote #{} is now rated {total:+}", rate.in_chat_id)
+        };
+
+        let answer = AnswerCallbackQuery::builder()
+            .callback_query_id(query.id())
+            .text(text)
+            .build();
+        self.retrier
+            .send(answer, |client, answer| async move {
+                client.answer_callback_query(answer).await
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Count deletes of messages that were never stored, which may be ones
+    /// populating missed
+    fn log_unknown_deletes(&self, ids: &[i64]) -> Result<()> {
+        let unknown = self.db.unknown_ids(ids)?;
+        if unknown.is_empty() {
+            return Ok(());
+        }
+
+        let total = self.unknown_deletes.get() + unknown.len() as u64;
+        self.unknown_deletes.set(total);
+
+        let in_chat_ids = unknown
+            .iter()
+            .map(|&id| tdlib::server_message_id(id))
+            .collect::<Vec<_>>();
+        debug!("Deleted messages {in_chat_ids:?} were never stored, {total} such delete(s) so far");
+
+        Ok(())
+    }
+
+    /// Fetch media quotes from the source chat again, for fresh file ids
+    async fn refresh_media(&self, in_chat_ids: &[i64]) -> Result<()> {
+        let names = Names::default();
+        for &in_chat_id in in_chat_ids {
+            let Some(msg) = populate::fetch(&self.client, self.config, in_chat_id).await? else {
+                debug!("Media quote {in_chat_id} is gone, skip");
+                continue;
+            };
+
+            let record = self
+                .chat
+                .record(&self.client, self.config, &names, msg, in_chat_id)
+                .await?;
+            self.live_writes.touch([record.id]);
+            self.db.insert_one(&record)?;
+        }
+
+        Ok(())
+    }
+
+    /// The quote the user has sent `repeat_limit` times or more in a row and
+    /// how many times, if any
+    fn repeat_streak(&self, user_id: i64) -> Result<Option<(i64, u32)>> {
+        let Some(limit) = self.config.repeat_limit else {
+            return Ok(None);
+        };
+
+        self.db
+            .repeat_streak(user_id)?
+            .filter(|(_, times)| *times >= limit)
+            .pipe(Ok)
+    }
+
+    /// Whether a send of `in_chat_id` continues a streak past `repeat_limit`,
+    /// so it shouldn't count
+    fn is_repeating(&self, user_id: i64, in_chat_id: i64) -> Result<bool> {
+        self.repeat_streak(user_id)?
+            .map_or(false, |(repeated, _)| repeated == in_chat_id)
+            .pipe(Ok)
+    }
+
+    /// The quote a `report <id>` query is about, if reports are enabled and
+    /// it's stored
+    fn report_query(&self, query: &str) -> Result<Option<i64>> {
+        if !self.config.reports {
+            return Ok(None);
+        }
+
+        let Some(id) = query
+            .strip_prefix("report ")
+            .map(|id| id.trim().trim_start_matches('#'))
+            .and_then(|id| id.parse().ok())
+        else {
+            return Ok(None);
+        };
+
+        Ok(self.db.exists(id)?.then_some(id))
+    }
+
+    /// Keep the last `recent_memory` quotes shown to the user
+    fn remember_shown(&self, user_id: i64, shown: impl IntoIterator<Item = i64>) {
+        let memory = self.config.recent_memory;
+        if memory == 0 {
+            return;
+        }
+
+        let mut recently_shown = self.recently_shown.borrow_mut();
+        let recent = recently_shown.entry(user_id).or_default();
+        recent.extend(shown);
+        if recent.len() > memory {
+            recent.drain(..recent.len() - memory);
+        }
+    }
+
+    /// The replica if it's configured and caught up with the primary, the
+    /// primary otherwise
+    fn reader(&self) -> &Messages {
+        let Some(replica) = &self.read_db else {
+            return &self.db;
+        };
+
+        let lag = match (self.db.latest_date(), replica.latest_date()) {
+            (Ok(primary), Ok(replica)) => primary.unwrap_or_default() - replica.unwrap_or_default(),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Failed to check replica lag, reading from the primary: {e:#}");
+                return &self.db;
+            }
+        };
+
+        if lag > self.config.read_db_max_lag_secs as i64 {
+            debug!("Replica is {lag}s behind, reading from the primary");
+            return &self.db;
+        }
+
+        replica
+    }
+
+    /// Whether the user gets the "switch to PM" button, which is shown until
+    /// an answer carrying it went through. Read-only instances can't remember
+    /// showing it, so leave that to the writer.
+    fn is_first_visit(&self, user_id: i64) -> Result<bool> {
+        Ok(!self.db.is_welcomed(user_id)?)
+    }
+
+    /// Chat sends are counted under, see `per_chat_stats`
+    fn count_chat(&self) -> i64 {
+        if self.config.per_chat_stats {
+            self.chat.id
+        } else {
+            db::UNSCOPED_CHAT
+        }
+    }
+
+    /// Chat the stats card counts sends from, `None` for all of them
+    fn stats_chat(&self) -> Option<i64> {
+        self.config.per_chat_stats.then_some(self.chat.id)
+    }
+
+    fn is_blocked(&self, user_id: i64) -> Result<bool> {
+        Ok(self.config.user_blocklist.contains(&user_id) || self.db.is_blocked(user_id)?)
+    }
+
+    async fn send_text(&self, chat_id: i64, text: impl AsRef<str>) -> Result<()> {
+        let message = SendMessage::builder()
+            .chat_id(chat_id)
+            .input_message_content(
+                FormattedText::builder()
+                    .text(text)
+                    .build()
+                    .pipe(|text| InputMessageText::builder().text(text).build())
+                    .pipe(InputMessageContent::InputMessageText),
+            )
+            .build();
+        self.retrier
+            .send(message, |client, message| async move {
+                client.send_message(message).await
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    fn filter(&self) -> Filter {
+        self.config
+            .filter(self.chat.source_type, Some(self.chat.id))
+    }
+}
+
+#[derive(RedactedDebug, Deserialize)]
+pub struct Config {
+    #[redacted]
+    pub bot_token: String,
+    pub chat_name: String,
+    #[redacted]
+    pub api_id: i32,
+    #[redacted]
+    pub api_hash: String,
+    // Each of the above can be read from a file instead, see
+    // `Config::merge_secret_files`
+    #[serde(default)]
+    pub bot_token_file: Option<PathBuf>,
+    #[serde(default)]
+    pub api_id_file: Option<PathBuf>,
+    #[serde(default)]
+    pub api_hash_file: Option<PathBuf>,
+
+    /// Proxy to reach Telegram through, `socks5://` or `http://`, optionally
+    /// with `user:password@`. Used from the first connection on, including
+    /// logging in.
+    #[redacted]
+    pub proxy_url: Option<String>,
+
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+
+    /// Location of the database, `main.db` in `data_dir` by default
+    #[serde(default)]
+    pub db_path: Option<PathBuf>,
+
+    /// Read-only copy of the database, e.g. restored by litestream, to serve
+    /// inline queries from. Writes always go to `db_path`.
+    #[serde(default)]
+    pub read_db_path: Option<PathBuf>,
+
+    /// Run as one of several bots sharing a database: open it read-only,
+    /// never populate, and only answer inline queries and private messages.
+    /// Exactly one instance sharing the database must not be read-only, see
+    /// the README.
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Delete the TDLib session in `data_dir` if it belongs to another bot than
+    /// `bot_token`, instead of refusing to start
+    #[serde(default)]
+    pub reset_mismatched_session: bool,
+
+    /// Timezone dates are shown and parsed in, e.g. `Asia/Shanghai`. Defaults
+    /// to `UTC`.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+
+    /// Locale dates are formatted for, e.g. `zh_CN`. Defaults to `en_US`.
+    #[serde(default = "default_locale")]
+    pub locale: String,
+
+    /// How far behind in seconds the newest message of the replica may be
+    /// before reads fall back to the primary
+    #[serde(default = "default_read_db_max_lag_secs")]
+    pub read_db_max_lag_secs: u64,
+
+    #[serde(default)]
+    pub skip_populate: bool,
+
+    /// First in-chat id of the source chat. Populating starts here instead of
+    /// at 1, or at the first stored message when unset. Needed for chats whose
+    /// ids start past `populate_empty_threshold`.
+    pub min_id: Option<i64>,
+
+    /// Only populate ids from `populate_from_id` up to `populate_to_id`, both
+    /// inclusive, e.g. to get a range that was stored wrong again with
+    /// `/repopulate --force` without touching the rest. Either end may be left
+    /// open.
+    pub populate_from_id: Option<i64>,
+    pub populate_to_id: Option<i64>,
+
+    /// Populating stops after this many ids in a row turn out to be missing,
+    /// which is taken as the end of the chat
+    #[serde(default = "default_populate_empty_threshold")]
+    pub populate_empty_threshold: u32,
+
+    /// Populating stops after running for this many seconds. Stored messages
+    /// are skipped quickly, so the next run picks up about where this one
+    /// stopped. Unlimited by default.
+    pub populate_time_budget_secs: Option<u64>,
+
+    /// Times populating retries getting a message before skipping it. Flood
+    /// waits are waited out without counting as a retry.
+    #[serde(default = "default_populate_retries")]
+    pub populate_retries: u32,
+
+    /// Text of the "switch to PM" button shown above inline results, once to
+    /// each user who hasn't sent a quote yet. The button is hidden when unset.
+    #[serde(default)]
+    pub switch_pm_text: Option<String>,
+
+    /// Start parameter passed to the bot when the "switch to PM" button is
+    /// pressed
+    #[serde(default = "default_switch_pm_parameter")]
+    pub switch_pm_parameter: String,
+
+    /// Link appended as a last result to every non-empty answer, for browsing
+    /// past what fits in inline results, e.g. the channel or a web UI. Hidden
+    /// when unset.
+    #[serde(default)]
+    pub browse_all_url: Option<String>,
+
+    /// Shown as the only result while there are no quotes to serve because
+    /// the database is empty or being populated
+    #[serde(default = "default_loading_text")]
+    pub loading_text: String,
+
+    /// Forms of "quote" on the stats card, for each plural category of the
+    /// `locale`'s language: `one`, `few`, `many` and `other`. `few` and `many`
+    /// fall back to `other`. Defaults to English.
+    #[serde(default = "default_quote_forms")]
+    pub quote_forms: plural::Forms,
+
+    /// Queries shorter than this many characters aren't searched, and get
+    /// `short_query_text` instead. The same goes for names after `@` and
+    /// topics after `?`. Empty queries still get random quotes. Off by
+    /// default.
+    #[serde(default)]
+    pub min_query_len: usize,
+
+    /// Shown as the only result for queries shorter than `min_query_len`
+    #[serde(default = "default_short_query_text")]
+    pub short_query_text: String,
+
+    /// Shown as the only result while serving is paused with `/pause`
+    #[serde(default = "default_paused_text")]
+    pub paused_text: String,
+
+    /// Keep serving paused across restarts until `/resume`. Otherwise the bot
+    /// always starts serving.
+    #[serde(default)]
+    pub persist_pause: bool,
+
+    /// Title of the `browse_all_url` result
+    #[serde(default = "default_browse_all_text")]
+    pub browse_all_text: String,
+
+    /// Reply to `/start`, `/help` and anything else sent to the bot in private
+    /// chat. `{bot}` is replaced with the bot's username.
+    #[serde(default = "default_help_text")]
+    pub help_text: String,
+
+    /// Inline queries from the same user arriving within this window (in
+    /// milliseconds) replace the previous one, only the latest is answered. `0`
+    /// disables debouncing.
+    #[serde(default = "default_inline_debounce_ms")]
+    pub inline_debounce_ms: u64,
+
+    /// Whether `chat_name` is a broadcast channel or a discussion group. Detected
+    /// from the chat when unset.
+    #[serde(default)]
+    pub source_type: Option<SourceType>,
+
+    /// Ordering of search results, one of `random`, `recent` (default) or
+    /// `relevance`
+    #[serde(default)]
+    pub search_order: SearchOrder,
+
+    /// Which messages are served: `forwarded`, `all` or `original`. Defaults to
+    /// `forwarded` for channels and `all` for groups.
+    #[serde(default)]
+    pub serve: Option<Serve>,
+
+    /// Updates taking longer than this (in milliseconds) to handle are logged as
+    /// warnings
+    #[serde(default = "default_slow_update_ms")]
+    pub slow_update_ms: u64,
+
+    /// How many times an answer or message is sent again after a short flood
+    /// wait or a transient error. Defaults to 2.
+    #[serde(default = "default_send_retries")]
+    pub send_retries: u32,
+
+    /// Log and count deletes of messages that were never stored, at debug
+    /// level. Many of them hint at gaps left by populating.
+    #[serde(default)]
+    pub log_unknown_deletes: bool,
+
+    /// What happens to stored messages deleted from the chat. `soft` (default)
+    /// keeps them around, hidden, until `realmkbot db purge` is run, `hard` removes
+    /// them right away.
+    #[serde(default)]
+    pub delete_mode: DeleteMode,
+
+    /// How many times TDLib initialization is attempted before giving up
+    #[serde(default = "default_init_attempts")]
+    pub init_attempts: u32,
+
+    /// Delay (in milliseconds) before the first initialization retry, doubled
+    /// after every failed attempt
+    #[serde(default = "default_init_backoff_ms")]
+    pub init_backoff_ms: u64,
+
+    /// Capacity of the queue between TDLib and the update loop. Updates are held
+    /// back by TDLib once it's full, so busy chats may need a bigger one.
+    #[serde(default = "default_update_buffer")]
+    pub update_buffer: usize,
+
+    /// Stored messages sent more than this many days ago are deleted for good.
+    /// Kept forever by default.
+    pub retention_days: Option<u64>,
+
+    /// Once more messages than this are stored, the ones served least
+    /// recently, never served first and oldest first among those, are deleted
+    /// for good after each insert. Populating doesn't fetch evicted messages
+    /// again, even when forced. Each insert counts the stored messages, and
+    /// those over the cap order the whole table to pick which go, so a cap of
+    /// millions makes inserts noticeably slower. Unlimited by default.
+    pub max_db_rows: Option<u64>,
+
+    /// How often reactions of a sample of stored messages are fetched again,
+    /// in seconds. Telegram only sends reaction updates for some messages, so
+    /// without this reactions of older ones go stale. Off by default.
+    pub reaction_refresh_secs: Option<u64>,
+
+    /// How many messages each reaction refresh looks at, half of them the
+    /// newest and half the most reacted. At most 100, which Telegram returns
+    /// in one go. Defaults to 50.
+    #[serde(default = "default_reaction_refresh_sample")]
+    pub reaction_refresh_sample: usize,
+
+    /// How often messages past `retention_days` are pruned, in seconds
+    #[serde(default = "default_prune_interval_secs")]
+    pub prune_interval_secs: u64,
+
+    /// How long to wait on shutdown for debounced inline queries to be answered
+    /// and a running repopulate to finish
+    #[serde(default = "default_shutdown_grace_ms")]
+    pub shutdown_grace_ms: u64,
+
+    /// Whether messages sent while the bot was offline are processed when their
+    /// updates arrive after startup. Populating still picks them up otherwise.
+    #[serde(default = "default_catch_up")]
+    pub catch_up: bool,
+
+    /// Tokio runtime to run on, `current_thread` or `multi_thread`. Defaults to
+    /// `current_thread`, which is plenty for small chats.
+    #[serde(default)]
+    pub runtime: RuntimeFlavor,
+
+    /// Worker threads of the `multi_thread` runtime, defaults to the number of
+    /// CPU cores
+    pub worker_threads: Option<usize>,
+
+    /// User ids allowed to run admin commands in private chat with the bot
+    #[serde(default)]
+    pub admins: Vec<i64>,
+
+    /// User ids whose sends are not counted. Users banned with `/ban` are
+    /// blocked as well.
+    #[serde(default)]
+    pub user_blocklist: Vec<i64>,
+
+    /// Only serve forwarded messages originally from these user or chat ids.
+    /// Others are stored but never served, and changing the list applies to
+    /// them right away. Messages posted directly in the source chat aren't
+    /// affected. All origins are served when empty.
+    #[serde(default)]
+    pub forward_origins: Vec<i64>,
+
+    /// What to do with messages the source chat forwarded from itself. `keep`
+    /// (default) treats them as any forward, `skip` never serves them, and
+    /// `original` serves them as if they were posted directly.
+    #[serde(default)]
+    pub self_forwards: SelfForwards,
+
+    /// How inline results are laid out. Telegram has no explicit switch for this,
+    /// clients show a gallery when all results are photos, animations or
+    /// stickers, so `gallery` only serves those. Defaults to `list`.
+    #[serde(default)]
+    pub layout: Layout,
+
+    /// Longest text in characters stored as a quote. Longer messages are still
+    /// stored, but without their text, so they're only served as media if at
+    /// all. Unlimited by default.
+    pub max_quote_len: Option<usize>,
+
+    /// Drop texts made up of emoji, punctuation or whitespace only, the same
+    /// way as overly long ones. Applies to messages stored from now on, use
+    /// `/repopulate --force` for existing ones.
+    #[serde(default)]
+    pub skip_symbol_only: bool,
+
+    /// Serve photos, animations and stickers without a caption too. Otherwise
+    /// only messages with text are served.
+    #[serde(default)]
+    pub include_media: bool,
+
+    /// How search queries match quotes: `substring` matches anywhere, `word`
+    /// only matches whole words. Queries in CJK scripts are always matched as
+    /// substrings. Defaults to `substring`.
+    #[serde(default)]
+    pub match_mode: MatchMode,
+
+    /// Where the user's stats card is shown for empty queries: `top`, `bottom`
+    /// or `hidden`. Defaults to `top`.
+    #[serde(default)]
+    pub stats_position: StatsPosition,
+
+    /// Count quotes sent by each user per source chat rather than across all
+    /// of them. Counts from before are moved over to the source chat on
+    /// start. Off by default.
+    #[serde(default)]
+    pub per_chat_stats: bool,
+
+    /// Quotes offered for empty queries: `random` (default), `recent` for the
+    /// newest ones, or `none` for just the stats card
+    #[serde(default)]
+    pub empty_query_mode: EmptyQueryMode,
+
+    /// Appended to sent quotes on a line of its own, e.g. `— via @{bot}`.
+    /// `{bot}` is replaced by the bot's username and `{link}` by a link to
+    /// the quote, left empty for basic groups. Empty by default.
+    #[serde(default)]
+    pub footer: String,
+
+    /// Title of text results. `{id}`, `{date}`, `{author}`, `{reactions}` and
+    /// `{text}` are replaced by the quote's. Defaults to `{text}`.
+    #[serde(default = "default_title_template")]
+    pub title_template: String,
+
+    /// Description of text results, with the same placeholders as
+    /// `title_template`. Defaults to `#{id}`.
+    #[serde(default = "default_description_template")]
+    pub description_template: String,
+
+    /// URL of an image shown next to text results, e.g. the bot's logo. Media
+    /// results show the media itself. None by default.
+    pub article_thumbnail_url: Option<String>,
+
+    /// Let users report quotes with a `report <id>` inline query, reviewed by
+    /// admins with `/reports`
+    #[serde(default)]
+    pub reports: bool,
+
+    /// Put 👍 and 👎 buttons under sent quotes. Ratings make well liked quotes
+    /// come up more often in random picks.
+    #[serde(default)]
+    pub ratings: bool,
+
+    /// How many random quotes shown to a user are remembered and picked last
+    /// the next time, so quick re-queries don't repeat them. 0 disables it.
+    #[serde(default = "default_recent_memory")]
+    pub recent_memory: usize,
+
+    /// OTLP collector to export tracing spans to, e.g. `http://localhost:4317`.
+    /// Needs the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+
+    /// Prime the database caches with a few queries before serving users
+    #[serde(default)]
+    pub warmup: bool,
+
+    /// Sends of the same quote by the same user within this many seconds are
+    /// counted once
+    #[serde(default = "default_send_dedup_secs")]
+    pub send_dedup_secs: u64,
+
+    /// Once a user has sent the same quote this many times in a row, further
+    /// sends of it aren't counted and empty queries show them a nudge to pick
+    /// another one. Off by default.
+    pub repeat_limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    List,
+    Gallery,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptyQueryMode {
+    #[default]
+    Random,
+    Recent,
+    None,
+}
+
+/// Where the personal stats card goes in answers to empty queries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsPosition {
+    #[default]
+    Top,
+    Bottom,
+    Hidden,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    Substring,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    #[default]
+    Soft,
+    Hard,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    #[default]
+    CurrentThread,
+    MultiThread,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceType {
+    /// Quotes are forwarded into a broadcast channel
+    Channel,
+    /// Quotes are posted by members of a group, attributed to their sender
+    Group,
+}
+
+fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .expect("data dir cannot be found")
+        .join("realmkbot")
+}
+
+fn default_populate_empty_threshold() -> u32 {
+    populate::DEFAULT_EMPTY_THRESHOLD
+}
+
+fn default_populate_retries() -> u32 {
+    2
+}
+
+fn default_reaction_refresh_sample() -> usize {
+    DEFAULT_REACTION_REFRESH_SAMPLE
+}
+
+fn default_prune_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    5000
+}
+
+fn default_read_db_max_lag_secs() -> u64 {
+    60
+}
+
+fn default_recent_memory() -> usize {
+    50
+}
+
+fn default_switch_pm_parameter() -> String {
+    "start".to_owned()
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+fn default_locale() -> String {
+    "en_US".to_owned()
+}
+
+fn default_title_template() -> String {
+    "{text}".to_owned()
+}
+
+fn default_description_template() -> String {
+    "#{id}".to_owned()
+}
+
+fn default_quote_forms() -> plural::Forms {
+    plural::Forms {
+        one: "quote".to_owned(),
+        few: None,
+        many: None,
+        other: "quotes".to_owned(),
+    }
+}
+
+fn default_short_query_text() -> String {
+    "Type a little more to search".to_owned()
+}
+
+fn default_paused_text() -> String {
+    "Under maintenance, back soon".to_owned()
+}
+
+fn default_loading_text() -> String {
+    "Still collecting quotes, check back soon".to_owned()
+}
+
+fn default_browse_all_text() -> String {
+    "Browse all".to_owned()
+}
+
+fn default_help_text() -> String {
+    "Type @{bot} followed by a few words in any chat to search for quotes, @{bot} @name for \
+     quotes by someone, or just @{bot} for random ones"
+        .to_owned()
+}
+
+fn default_inline_debounce_ms() -> u64 {
+    250
+}
+
+fn default_slow_update_ms() -> u64 {
+    500
+}
+
+fn default_send_retries() -> u32 {
+    2
+}
+
+fn default_init_attempts() -> u32 {
+    5
+}
+
+fn default_init_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_update_buffer() -> usize {
+    1024
+}
+
+fn default_catch_up() -> bool {
+    true
+}
+
+fn default_send_dedup_secs() -> u64 {
+    10
+}
 
 impl Config {
     pub fn load<'a>() -> &'a Self {
@@ -280,20 +1985,257 @@ impl Config {
 
             info!("Config dir: {}", config_dir.join("config.toml").display());
 
-            Figment::new()
+            let files = Figment::new()
                 .merge(Json::file(config_dir.join("config.json")))
                 .merge(Toml::file(config_dir.join("config.toml")))
                 .merge(Json::file("config.json"))
-                .merge(Toml::file("config.toml"))
+                .merge(Toml::file("config.toml"));
+            Config::warn_unknown_keys(&files);
+
+            files
                 .merge(Env::raw())
-                .extract()
+                .pipe(Config::merge_secret_files)
+                .expect("Failed to read secret files")
+                .extract::<Config>()
                 .expect("Failed to load config")
+                .tap(|config| config.validate().expect("Invalid config"))
         });
 
         &CONFIG
     }
 
+    /// Point out keys in config files that aren't config fields, likely typos
+    /// silently left at their defaults otherwise. The environment is left
+    /// alone, as it's full of unrelated variables.
+    fn warn_unknown_keys(files: &figment::Figment) {
+        let Ok(dict) = files.extract::<figment::value::Dict>() else {
+            // Reported by the full extraction
+            return;
+        };
+
+        let mut fields: &[&str] = &[];
+        let _ = Config::deserialize(FieldNames(&mut fields));
+
+        for key in dict.keys() {
+            if !fields.contains(&key.as_str()) {
+                warn!("Unknown config key `{key}`, ignored");
+            }
+        }
+    }
+
+    /// Read secrets from the files named by `<key>_file`, as Docker and
+    /// Kubernetes mount them
+    fn merge_secret_files(mut figment: figment::Figment) -> Result<figment::Figment> {
+        for key in SECRETS {
+            let file_key = format!("{key}_file");
+            let Ok(Some(path)) = figment.extract_inner::<Option<PathBuf>>(&file_key) else {
+                continue;
+            };
+            if figment.contains(key) {
+                bail!("Only one of `{key}` and `{file_key}` may be set");
+            }
+
+            let secret = std::fs::read_to_string(&path)
+                .wrap_err_with(|| format!("Failed to read `{file_key}` {}", path.display()))?;
+            let secret = secret.trim();
+
+            figment = match key {
+                "api_id" => figment.merge((
+                    key,
+                    secret
+                        .parse::<i32>()
+                        .wrap_err_with(|| format!("Invalid api id in {}", path.display()))?,
+                )),
+                _ => figment.merge((key, secret)),
+            };
+        }
+
+        Ok(figment)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Err(Error::Config(msg));
+
+        if self.update_buffer == 0 {
+            return invalid("`update_buffer` must be greater than 0".to_owned());
+        }
+        if let (Some(from), Some(to)) = (self.populate_from_id, self.populate_to_id) {
+            if from > to {
+                return invalid(format!(
+                    "`populate_from_id` ({from}) must not be past `populate_to_id` ({to})"
+                ));
+            }
+        }
+        if self.reaction_refresh_secs == Some(0) {
+            return invalid("`reaction_refresh_secs` must be greater than 0".to_owned());
+        }
+        if !(1..=tdlib::MAX_GET_MESSAGES_BATCH).contains(&self.reaction_refresh_sample) {
+            return invalid(format!(
+                "`reaction_refresh_sample` must be between 1 and {}",
+                tdlib::MAX_GET_MESSAGES_BATCH
+            ));
+        }
+        if self.prune_interval_secs == 0 {
+            return invalid("`prune_interval_secs` must be greater than 0".to_owned());
+        }
+        if self.worker_threads == Some(0) {
+            return invalid("`worker_threads` must be greater than 0".to_owned());
+        }
+        for (name, template) in [
+            ("title_template", &self.title_template),
+            ("description_template", &self.description_template),
+        ] {
+            if let Some(placeholder) = db::unknown_placeholder(template) {
+                return invalid(format!(
+                    "Unknown placeholder `{{{placeholder}}}` in `{name}`"
+                ));
+            }
+        }
+        if let Err(e) = self.proxy() {
+            return invalid(format!("{e:#}"));
+        }
+        if Locale::try_from(self.locale.as_str()).is_err() {
+            return invalid(format!("Unknown `locale` `{}`", self.locale));
+        }
+
+        Ok(())
+    }
+
+    pub fn date_format(&self) -> DateFormat {
+        DateFormat {
+            tz: self.timezone,
+            locale: Locale::try_from(self.locale.as_str()).unwrap_or(Locale::POSIX),
+        }
+    }
+
+    pub fn proxy(&self) -> Result<Option<tdlib::Proxy>> {
+        self.proxy_url
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .wrap_err("Invalid `proxy_url`")
+    }
+
+    pub fn text_policy(&self) -> TextPolicy {
+        TextPolicy {
+            max_len: self.max_quote_len,
+            require_alphanumeric: self.skip_symbol_only,
+        }
+    }
+
+    /// What's served to users from a source of the given type. Self-forwards
+    /// can only be told apart knowing the id of the source chat.
+    pub fn filter(&self, source_type: SourceType, chat_id: Option<i64>) -> Filter {
+        // `is_forwarded` means little in groups, where quotes are posted directly
+        let serve = self.serve.unwrap_or(match source_type {
+            SourceType::Channel => Serve::Forwarded,
+            SourceType::Group => Serve::All,
+        });
+
+        Filter {
+            serve,
+            gallery_only: self.layout == Layout::Gallery,
+            include_media: self.include_media,
+            whole_words: self.match_mode == MatchMode::Word,
+            forward_origins: self.forward_origins.clone(),
+            self_forwards: self.self_forwards,
+            source_chat: chat_id,
+            ..Filter::default()
+        }
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.db_path
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("main.db"))
+    }
+
     pub fn tdlib_dir(&self) -> PathBuf {
         self.data_dir.join("tdlib")
     }
 }
+
+/// Deserializer that only records the field names of the struct asked for, as
+/// known to serde, then gives up
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("only structs have field names"))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(serde::de::Error::custom("only field names are recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permalinks() {
+        assert_eq!(
+            ChatLink::Public.permalink("realmk", 42).as_deref(),
+            Some("https://t.me/realmk/42")
+        );
+        // Private chats have no username to link by
+        assert_eq!(
+            ChatLink::Private {
+                supergroup_id: 1234
+            }
+            .permalink("realmk", 42)
+            .as_deref(),
+            Some("tg://privatepost?channel=1234&post=42")
+        );
+        assert_eq!(ChatLink::None.permalink("realmk", 42), None);
+    }
+
+    #[test]
+    fn secret_files() {
+        let path = std::env::temp_dir().join(format!("realmkbot-token-{}", std::process::id()));
+        std::fs::write(&path, "123:abc\n").unwrap();
+        let figment = |pairs: &[(&str, &str)]| {
+            pairs.iter().fold(figment::Figment::new(), |figment, pair| {
+                figment.merge(*pair)
+            })
+        };
+        let path_str = path.to_str().unwrap();
+
+        // Read from the file, trailing newline and all
+        let merged = Config::merge_secret_files(figment(&[
+            ("api_hash", "hash"),
+            ("bot_token_file", path_str),
+        ]))
+        .unwrap();
+        assert_eq!(
+            merged.extract_inner::<String>("bot_token").unwrap(),
+            "123:abc"
+        );
+        assert_eq!(merged.extract_inner::<String>("api_hash").unwrap(), "hash");
+
+        // Both set is ambiguous
+        assert!(Config::merge_secret_files(figment(&[
+            ("bot_token", "456:def"),
+            ("bot_token_file", path_str),
+        ]))
+        .is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(Config::merge_secret_files(figment(&[("bot_token_file", path_str)])).is_err());
+    }
+}
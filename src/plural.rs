@@ -0,0 +1,120 @@
+//! Counts followed by the right plural form of a word, following the CLDR
+//! rules of the configured locale's language
+
+use serde::Deserialize;
+
+/// Forms of a word for each plural category. Languages without `few` or
+/// `many` leave them out, and they fall back to `other`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Forms {
+    pub one: String,
+    #[serde(default)]
+    pub few: Option<String>,
+    #[serde(default)]
+    pub many: Option<String>,
+    pub other: String,
+}
+
+impl Forms {
+    /// `n` followed by the form the locale uses for it, e.g. `1 quote` or
+    /// `0 quotes`
+    pub fn count(&self, locale: &str, n: u64) -> String {
+        let form = match category(locale, n) {
+            Category::One => &self.one,
+            Category::Few => self.few.as_ref().unwrap_or(&self.other),
+            Category::Many => self.many.as_ref().unwrap_or(&self.other),
+            Category::Other => &self.other,
+        };
+
+        format!("{n} {form}")
+    }
+}
+
+enum Category {
+    One,
+    Few,
+    Many,
+    Other,
+}
+
+/// Category of a whole number in the language of a locale such as `en_US`.
+/// Languages not listed here pluralize like English.
+fn category(locale: &str, n: u64) -> Category {
+    let language = locale.split(['_', '-']).next().unwrap_or_default();
+    let (rem10, rem100) = (n % 10, n % 100);
+
+    match language {
+        "ja" | "ko" | "zh" | "id" | "ms" | "th" | "vi" => Category::Other,
+        "fr" if n <= 1 => Category::One,
+        "ru" | "uk" | "be" => match rem10 {
+            1 if rem100 != 11 => Category::One,
+            2..=4 if !(12..=14).contains(&rem100) => Category::Few,
+            _ => Category::Many,
+        },
+        "pl" => match rem10 {
+            _ if n == 1 => Category::One,
+            2..=4 if !(12..=14).contains(&rem100) => Category::Few,
+            _ => Category::Many,
+        },
+        "cs" | "sk" => match n {
+            1 => Category::One,
+            2..=4 => Category::Few,
+            _ => Category::Other,
+        },
+        _ if n == 1 => Category::One,
+        _ => Category::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forms(one: &str, few: Option<&str>, many: Option<&str>, other: &str) -> Forms {
+        Forms {
+            one: one.to_owned(),
+            few: few.map(str::to_owned),
+            many: many.map(str::to_owned),
+            other: other.to_owned(),
+        }
+    }
+
+    #[test]
+    fn english() {
+        let quotes = forms("quote", None, None, "quotes");
+        assert_eq!(quotes.count("en_US", 0), "0 quotes");
+        assert_eq!(quotes.count("en_US", 1), "1 quote");
+        assert_eq!(quotes.count("en_US", 2), "2 quotes");
+        assert_eq!(quotes.count("en_US", 11), "11 quotes");
+        // Unlisted languages go the English way
+        assert_eq!(quotes.count("de_DE", 1), "1 quote");
+    }
+
+    #[test]
+    fn french() {
+        let quotes = forms("citation", None, None, "citations");
+        // Zero is singular in French
+        assert_eq!(quotes.count("fr_FR", 0), "0 citation");
+        assert_eq!(quotes.count("fr_FR", 1), "1 citation");
+        assert_eq!(quotes.count("fr_FR", 2), "2 citations");
+    }
+
+    #[test]
+    fn russian() {
+        let quotes = forms("цитата", Some("цитаты"), Some("цитат"), "цитаты");
+        assert_eq!(quotes.count("ru_RU", 0), "0 цитат");
+        assert_eq!(quotes.count("ru_RU", 1), "1 цитата");
+        assert_eq!(quotes.count("ru_RU", 3), "3 цитаты");
+        assert_eq!(quotes.count("ru_RU", 5), "5 цитат");
+        assert_eq!(quotes.count("ru_RU", 11), "11 цитат");
+        assert_eq!(quotes.count("ru_RU", 21), "21 цитата");
+        assert_eq!(quotes.count("ru_RU", 22), "22 цитаты");
+    }
+
+    #[test]
+    fn missing_forms_fall_back_to_other() {
+        let quotes = forms("cytat", None, None, "cytaty");
+        assert_eq!(quotes.count("pl_PL", 5), "5 cytaty");
+        assert_eq!(quotes.count("ja_JP", 1), "1 cytaty");
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::Result;
+use rust_tdlib::{
+    client::{tdlib_client::TdJson, Client},
+    types::{GetChat, GetMessageLinkInfo, Message},
+};
+use tap::Pipe;
+
+use crate::{
+    db::{MessageRecord, Messages},
+    error::Error,
+    tdlib, Chat, Config, Names,
+};
+
+/// Missing ids in a row after which populating stops, unless configured
+/// otherwise. Deleted messages leave gaps, so this can't be 1.
+pub const DEFAULT_EMPTY_THRESHOLD: u32 = 10;
+
+/// Get a message of the source chat by its in-chat id, `None` if there's no
+/// such message
+pub async fn fetch(
+    client: &Client<TdJson>,
+    config: &Config,
+    in_chat_id: i64,
+) -> Result<Option<Message>, Error> {
+    GetMessageLinkInfo::builder()
+        .url(format!(
+            "tg:resolve?domain={}&post={}",
+            config.chat_name, in_chat_id
+        ))
+        .build()
+        .pipe(|s| client.get_message_link_info(s))
+        .await?
+        .message()
+        .to_owned()
+        .pipe(Ok)
+}
+
+/// Messages of the source chat written by live updates while a populate runs.
+///
+/// The update loop and a repopulate take turns on one thread and database
+/// calls don't yield, so their statements never interleave. What can race is a
+/// populate fetch still in flight while an update for the same message is
+/// written: the fetched copy is then older than the stored one. Invariants:
+///
+/// - Live writes always go straight to the database, they never wait on a
+///   populate. Awaiting a lock held by a populate from the update loop would
+///   deadlock, as the populate is only polled by that same loop.
+/// - A populate never overwrites a message touched by a live write during the
+///   run, e.g. bringing a deleted message back or resetting its reactions.
+/// - Reads are unaffected.
+#[derive(Debug, Default)]
+pub struct LiveWrites(RefCell<Option<HashSet<i64>>>);
+
+impl LiveWrites {
+    /// Record live writes to messages by TDLib id. Nothing is kept while not
+    /// populating.
+    pub fn touch(&self, ids: impl IntoIterator<Item = i64>) {
+        if let Some(touched) = self.0.borrow_mut().as_mut() {
+            touched.extend(ids);
+        }
+    }
+
+    fn begin(&self) {
+        *self.0.borrow_mut() = Some(HashSet::new());
+    }
+
+    fn end(&self) {
+        *self.0.borrow_mut() = None;
+    }
+
+    fn touched(&self, id: i64) -> bool {
+        self.0
+            .borrow()
+            .as_ref()
+            .map_or(false, |touched| touched.contains(&id))
+    }
+
+    /// Store a message fetched by a populate, unless a live write got to it
+    /// first. `false` if it was left alone.
+    fn store_fetched(&self, db: &Messages, record: &MessageRecord) -> Result<bool> {
+        if self.touched(record.id) {
+            return Ok(false);
+        }
+
+        db.insert_one(record)?;
+        Ok(true)
+    }
+}
+
+/// Scrapes the source chat for messages missing from the database. Owns
+/// everything it needs so it can run alongside the update loop.
+pub struct Populator {
+    pub config: &'static Config,
+    pub db: Rc<Messages>,
+    pub client: Client<TdJson>,
+    pub chat: Chat,
+    /// Fetch messages that are already stored again
+    pub force: bool,
+    pub live_writes: Rc<LiveWrites>,
+    /// Names of forward origins and group members looked up so far
+    pub names: Names,
+}
+
+/// What a populate run did
+#[derive(Debug, Clone, Default)]
+pub struct PopulateSummary {
+    /// Messages stored for the first time
+    pub added: usize,
+    /// Stored messages fetched again, only when forced
+    pub replaced: usize,
+    /// Stored or evicted messages left alone
+    pub skipped: usize,
+    /// Ids without a message, deleted or past the end of the chat
+    pub empty: usize,
+    /// Inclusive ranges of ids that couldn't be fetched even after retrying
+    pub failed: Vec<(i64, i64)>,
+    pub elapsed: Duration,
+}
+
+impl PopulateSummary {
+    fn fail(&mut self, id: i64) {
+        match self.failed.last_mut() {
+            Some((_, end)) if *end + 1 == id => *end = id,
+            _ => self.failed.push((id, id)),
+        }
+    }
+
+    /// Failed ranges, e.g. `3-5, 9`
+    pub fn failed_ranges(&self) -> String {
+        self.failed
+            .iter()
+            .map(|&(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{start}-{end}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Display for PopulateSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} message(s) added, {} replaced, {} skipped, {} empty id(s) in {:.1?}",
+            self.added, self.replaced, self.skipped, self.empty, self.elapsed
+        )?;
+
+        if !self.failed.is_empty() {
+            write!(f, ", failed to get {}", self.failed_ranges())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Populator {
+    #[tracing::instrument(skip_all, fields(chat_id = self.chat.id, force = self.force))]
+    pub async fn run(self) -> Result<PopulateSummary> {
+        self.live_writes.begin();
+        let res = self.populate().await;
+        self.live_writes.end();
+
+        res
+    }
+
+    async fn populate(&self) -> Result<PopulateSummary> {
+        info!("Populating{}", if self.force { " (forced)" } else { "" });
+        let started_at = Instant::now();
+
+        let min_id = self.min_id()?;
+        if min_id > 1 {
+            info!("Starting from id {min_id}");
+        }
+        let to_id = self.config.populate_to_id;
+        if let Some(to_id) = to_id {
+            info!("Stopping after id {to_id}");
+        }
+
+        let max_id = self.max_id().await?.map(|max_id| match to_id {
+            Some(to_id) => to_id.min(max_id),
+            None => max_id,
+        });
+        match max_id {
+            Some(max_id) => info!(
+                "{} of {} message(s) missing",
+                self.db.gap_count(min_id, max_id)?,
+                max_id - min_id + 1
+            ),
+            None => debug!("Unknown last message, can't count gaps"),
+        }
+
+        self.name_origins().await?;
+
+        let budget = self
+            .config
+            .populate_time_budget_secs
+            .map(Duration::from_secs);
+        let mut consecutive_empty_msg = 0;
+        let mut summary = PopulateSummary::default();
+
+        for id in min_id.. {
+            if to_id.map_or(false, |to_id| id > to_id) {
+                break;
+            }
+
+            if consecutive_empty_msg > self.config.populate_empty_threshold {
+                break;
+            }
+
+            if budget.map_or(false, |budget| started_at.elapsed() >= budget) {
+                info!("Time budget used up at id {id}, the rest is left for the next run");
+                break;
+            }
+
+            // Even when forced, or the next insert would evict them again
+            if self.db.is_evicted(id)? {
+                consecutive_empty_msg = 0;
+                summary.skipped += 1;
+                continue;
+            }
+
+            let exists = self.db.exists(id)?;
+            if exists && !self.force {
+                consecutive_empty_msg = 0;
+                summary.skipped += 1;
+                continue;
+            }
+
+            debug!("Getting {id}");
+            match self.store_retrying(id).await {
+                Ok(true) => consecutive_empty_msg = 0,
+                Ok(false) => {
+                    consecutive_empty_msg += 1;
+                    summary.empty += 1;
+                    continue;
+                }
+                // Not knowing whether there's a message, don't count it either way
+                Err(e) => {
+                    warn!("Failed to get {id}, moving on: {e:#}");
+                    summary.fail(id);
+                    continue;
+                }
+            }
+
+            if exists {
+                summary.replaced += 1;
+                debug!("Replaced");
+            } else {
+                summary.added += 1;
+                debug!("Added");
+            }
+        }
+
+        summary.elapsed = started_at.elapsed();
+        info!("Done, {summary}");
+        if !summary.failed.is_empty() {
+            warn!(
+                "Couldn't get {}, run /repopulate to try again",
+                summary.failed_ranges()
+            );
+        }
+
+        if let Some(max_rows) = self.config.max_db_rows {
+            self.db.evict_over(max_rows)?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Fetch and store a message, `false` if there's no message with the id.
+    /// Messages written by a live update meanwhile are left as they are.
+    async fn store(&self, id: i64) -> Result<bool> {
+        let Some(msg) = fetch(&self.client, self.config, id).await? else {
+            return Ok(false);
+        };
+
+        let record = self
+            .chat
+            .record(&self.client, self.config, &self.names, msg, id)
+            .await?;
+        if !self.live_writes.store_fetched(&self.db, &record)? {
+            debug!("{id} was updated while getting it, keep the update");
+        }
+
+        Ok(true)
+    }
+
+    /// [`Populator::store`], waiting out flood waits and retrying other errors
+    /// up to `populate_retries` times. Errors known to be permanent, e.g. a
+    /// request Telegram refused, aren't retried.
+    async fn store_retrying(&self, id: i64) -> Result<bool> {
+        let mut retries = 0;
+        let mut backoff = Duration::SECOND;
+
+        loop {
+            match self.store(id).await {
+                Ok(found) => return Ok(found),
+                Err(e) => match tdlib::flood_wait(&e) {
+                    Some(secs) => {
+                        warn!("Flood wait of {secs}s getting {id}");
+                        tdlib::sleep_flood_wait(secs).await;
+                    }
+                    None if Error::find(&e).map_or(false, |e| !e.is_transient()) => return Err(e),
+                    None if retries < self.config.populate_retries => {
+                        retries += 1;
+                        debug!("Failed to get {id}, retrying in {backoff:?}: {e:#}");
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Fill in names of stored forwards missing one, those stored before names
+    /// were or whose origin TDLib didn't know back then
+    async fn name_origins(&self) -> Result<()> {
+        let mut named = 0;
+        for origin in self.db.unnamed_origins()? {
+            if let Some(name) = self.names.get(&self.client, origin).await {
+                named += self.db.set_forward_name(origin, &name)?;
+            }
+        }
+
+        if named > 0 {
+            info!("Named {named} forwarded message(s)");
+        }
+
+        Ok(())
+    }
+
+    /// Id to start from: the configured `populate_from_id` or `min_id`, or
+    /// else the first stored message, as ids of chats that were migrated may
+    /// not start at 1
+    fn min_id(&self) -> Result<i64> {
+        start_id(
+            self.config.populate_from_id.or(self.config.min_id),
+            &self.db,
+        )
+    }
+
+    /// In-chat id of the last message in the source chat, if TDLib knows it
+    async fn max_id(&self) -> Result<Option<i64>> {
+        let chat = GetChat::builder()
+            .chat_id(self.chat.id)
+            .build()
+            .pipe(|s| self.client.get_chat(s))
+            .await?;
+
+        Ok(chat
+            .last_message()
+            .as_ref()
+            .map(|msg| tdlib::server_message_id(msg.id())))
+    }
+}
+
+/// [`Populator::min_id`], given the id configured to start from if any
+fn start_id(configured: Option<i64>, db: &Messages) -> Result<i64> {
+    if let Some(min_id) = configured {
+        return Ok(min_id);
+    }
+
+    Ok(db.min_id()?.unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rusqlite::OptionalExtension;
+
+    use super::*;
+
+    fn db() -> Messages {
+        Messages::open(":memory:").unwrap()
+    }
+
+    fn quote(in_chat_id: i64, text: &str) -> MessageRecord {
+        MessageRecord {
+            id: in_chat_id,
+            in_chat_id,
+            text: Some(text.to_owned()),
+            date: in_chat_id,
+            servable: true,
+            ..MessageRecord::default()
+        }
+    }
+
+    #[test]
+    fn starts_from_first_stored_id() {
+        let db = db();
+        assert_eq!(start_id(None, &db).unwrap(), 1);
+
+        for in_chat_id in [1000, 1001, 1003] {
+            db.insert_one(&quote(in_chat_id, "quote")).unwrap();
+        }
+        assert_eq!(start_id(None, &db).unwrap(), 1000);
+        // Only the missing id counts, not the thousand before the first one
+        assert_eq!(db.gap_count(1000, 1003).unwrap(), 1);
+
+        assert_eq!(start_id(Some(1), &db).unwrap(), 1);
+        assert_eq!(start_id(Some(1002), &db).unwrap(), 1002);
+    }
+
+    /// What a message should look like after a run of [`live_writes_win`]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Row {
+        text: String,
+        reactions: i64,
+        deleted: bool,
+    }
+
+    #[test]
+    fn live_writes_win() {
+        const IDS: u64 = 50;
+
+        let db = db();
+        let live = LiveWrites::default();
+        let mut expected = HashMap::<i64, Row>::new();
+        let mut touched = HashSet::new();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+
+        live.begin();
+        for step in 0..10_000_i64 {
+            // xorshift, for an interleaving that's the same on every run
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let id = (state % IDS) as i64 + 1;
+
+            match (state >> 32) % 4 {
+                // A populate storing what it fetched before the live writes
+                0 => {
+                    let stale = MessageRecord {
+                        reactions: -1,
+                        ..quote(id, "stale")
+                    };
+                    let stored = live.store_fetched(&db, &stale).unwrap();
+                    assert_eq!(stored, !touched.contains(&id));
+                    if stored {
+                        let row = expected.entry(id).or_insert(Row {
+                            text: String::new(),
+                            reactions: 0,
+                            deleted: false,
+                        });
+                        row.text = "stale".to_owned();
+                        row.reactions = -1;
+                    }
+                }
+                // An edit
+                1 => {
+                    live.touch([id]);
+                    touched.insert(id);
+                    db.insert_one(&MessageRecord {
+                        reactions: step,
+                        ..quote(id, &format!("edit {step}"))
+                    })
+                    .unwrap();
+                    let row = expected.entry(id).or_insert(Row {
+                        text: String::new(),
+                        reactions: 0,
+                        deleted: false,
+                    });
+                    row.text = format!("edit {step}");
+                    row.reactions = step;
+                }
+                2 => {
+                    live.touch([id]);
+                    touched.insert(id);
+                    db.delete(&[id]).unwrap();
+                    if let Some(row) = expected.get_mut(&id) {
+                        row.deleted = true;
+                    }
+                }
+                _ => {
+                    live.touch([id]);
+                    touched.insert(id);
+                    db.set_reactions(id, step).unwrap();
+                    if let Some(row) = expected.get_mut(&id) {
+                        row.reactions = step;
+                    }
+                }
+            }
+        }
+        live.end();
+
+        for id in 1..=IDS as i64 {
+            let stored = db
+                .query_row(
+                    "SELECT text, reactions, deleted_at IS NOT NULL FROM message WHERE id = ?1",
+                    [id],
+                    |row| {
+                        Ok(Row {
+                            text: row.get(0)?,
+                            reactions: row.get(1)?,
+                            deleted: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()
+                .unwrap();
+            assert_eq!(stored.as_ref(), expected.get(&id), "message {id}");
+        }
+
+        // Once the run is over, populates write again
+        assert!(live.store_fetched(&db, &quote(1, "next run")).unwrap());
+    }
+}
@@ -1,18 +1,34 @@
-use std::time::Duration;
+use std::{
+    fmt::{self, Display, Formatter},
+    future::Future,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use color_eyre::Result;
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Report, Result,
+};
 use rust_tdlib::{
     client::{
         auth_handler::ClientAuthStateHandler, tdlib_client::TdJson, AuthStateHandler, Client,
         ClientIdentifier, Worker,
     },
-    tdjson::set_log_verbosity_level,
+    tdjson::{self, set_log_verbosity_level},
     types::*,
 };
+use serde::Serialize;
+use serde_json::Value;
+use tap::Pipe;
 use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+use tracing::{Instrument, Span};
 
-use crate::Config;
+use crate::{
+    error::{self, Error},
+    Config,
+};
 
 #[derive(Debug)]
 pub struct WorkerHandle {
@@ -31,22 +47,275 @@ impl WorkerHandle {
     }
 }
 
-pub async fn init(config: &Config) -> Result<(Client<TdJson>, WorkerHandle)> {
-    let mut worker = Worker::builder()
-        .with_auth_state_handler(BotTokenHandler {
-            bot_token: config.bot_token.clone(),
-        })
-        .build()?;
-    let background = worker.start();
+/// Code and message of the error TDLib or Telegram answered a request with,
+/// if `err` was made of one, be it converted to an [`Error`] or not
+fn telegram_error(err: &Report) -> Option<(i32, &str)> {
+    err.chain().find_map(|err| {
+        if let Some(Error::Telegram { code, message }) = err.downcast_ref() {
+            return Some((*code, message.as_str()));
+        }
+
+        match err.downcast_ref()? {
+            rust_tdlib::errors::Error::TDLibError(err) => {
+                Some((err.code(), err.message().as_str()))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Whether the error is TDLib failing to find or access a chat
+pub fn is_chat_invalid(err: &Report) -> bool {
+    matches!(
+        telegram_error(err),
+        Some((
+            400,
+            "Chat not found" | "PEER_ID_INVALID" | "CHANNEL_INVALID"
+        ))
+    )
+}
+
+/// Whether the error is an inline query that can't be answered anymore,
+/// usually because answering took too long
+pub fn is_query_expired(err: &Report) -> bool {
+    match telegram_error(err) {
+        Some((400, message)) => {
+            message == "QUERY_ID_INVALID" || message.contains("query is too old")
+        }
+        _ => false,
+    }
+}
+
+/// Whether the error is Telegram rejecting a file id, e.g. one that expired
+pub fn is_file_invalid(err: &Report) -> bool {
+    match telegram_error(err) {
+        Some((400, message)) => {
+            matches!(
+                message,
+                "FILE_ID_INVALID" | "FILE_REFERENCE_EXPIRED" | "MEDIA_EMPTY"
+            ) || message.starts_with("Wrong remote file identifier")
+        }
+        _ => false,
+    }
+}
+
+/// Seconds Telegram asked to wait for if the error is a flood wait, i.e. `429
+/// Too Many Requests: retry after N` as TDLib puts it, or Telegram's own `420
+/// FLOOD_WAIT_N`
+pub fn flood_wait(err: &Report) -> Option<u64> {
+    let after = match telegram_error(err)? {
+        (429, message) => message.split_once("retry after ")?.1,
+        (420, message) => message.strip_prefix("FLOOD_WAIT_")?,
+        _ => return None,
+    };
+
+    after
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+/// Flood waits slept out so far, see [`sleep_flood_wait`]
+static FLOOD_WAITS: FloodWaits = FloodWaits {
+    count: AtomicU64::new(0),
+    secs: AtomicU64::new(0),
+};
+
+struct FloodWaits {
+    count: AtomicU64,
+    secs: AtomicU64,
+}
+
+/// Sleep out a flood wait of `secs`, counting it towards [`flood_waits`].
+/// Everything that waits before trying again after a flood wait goes through
+/// here.
+pub async fn sleep_flood_wait(secs: u64) {
+    let count = FLOOD_WAITS.count.fetch_add(1, Ordering::Relaxed) + 1;
+    let total_secs = FLOOD_WAITS.secs.fetch_add(secs, Ordering::Relaxed) + secs;
+    debug!("Sleeping out a flood wait of {secs}s, {count} flood wait(s) for {total_secs}s so far");
+
+    tokio::time::sleep(Duration::from_secs(secs)).await;
+}
+
+/// Flood waits slept out since the bot started, and the seconds spent in them
+pub fn flood_waits() -> (u64, u64) {
+    (
+        FLOOD_WAITS.count.load(Ordering::Relaxed),
+        FLOOD_WAITS.secs.load(Ordering::Relaxed),
+    )
+}
+
+/// Server id of a message, the one in its link and the `in_chat_id` of stored
+/// messages, out of its TDLib id. Both are 64-bit, TDLib ids being server ids
+/// shifted left by 20 bits.
+pub fn server_message_id(message_id: i64) -> i64 {
+    message_id >> 20
+}
+
+/// Most messages Telegram returns for a single `getMessages`
+pub const MAX_GET_MESSAGES_BATCH: usize = 100;
+
+/// Longest flood wait [`Retrier::send`] sits out before trying again. Waiting
+/// any longer, whoever the request is for has likely given up.
+const MAX_RETRY_WAIT_SECS: u64 = 5;
+
+/// Sends requests nobody waits on the answer of, trying again after short
+/// flood waits and transient errors. Updates are handled one at a time, so
+/// retries run in tasks of their own rather than holding up the update loop
+/// for the length of the wait.
+#[derive(Clone)]
+pub struct Retrier {
+    client: Client<TdJson>,
+    retries: u32,
+}
+
+impl Retrier {
+    pub fn new(client: Client<TdJson>, retries: u32) -> Self {
+        Retrier { client, retries }
+    }
+
+    /// Send `request` with `send`, handing it to a background task to try
+    /// again up to `retries` times if that's worth it. Only the first attempt
+    /// is waited on, failures of later ones are logged.
+    pub async fn send<R, T, F>(&self, request: R, send: fn(Client<TdJson>, R) -> F) -> Result<()>
+    where
+        R: Clone + Send + 'static,
+        F: Future<Output = Result<T, rust_tdlib::errors::Error>> + Send + 'static,
+    {
+        let Err(e) = attempt(&self.client, request.clone(), send).await else {
+            return Ok(());
+        };
+        let Some(wait) = retry_wait(&e, Duration::from_millis(100)) else {
+            return Err(e);
+        };
+        if self.retries == 0 {
+            return Err(e);
+        }
 
+        debug!("Request failed, retrying in {wait:?}: {e:#}");
+        let (client, retries) = (self.client.clone(), self.retries);
+        tokio::spawn(
+            async move {
+                let (mut last, mut wait) = (e, wait);
+                for retry in 1..=retries {
+                    match flood_wait(&last) {
+                        Some(secs) => sleep_flood_wait(secs).await,
+                        None => tokio::time::sleep(wait).await,
+                    }
+
+                    let Err(e) = attempt(&client, request.clone(), send).await else {
+                        return;
+                    };
+                    match retry_wait(&e, wait * 2) {
+                        Some(next) if retry < retries => {
+                            debug!("Request failed, retrying in {next:?}: {e:#}");
+                            (last, wait) = (e, next);
+                        }
+                        _ => {
+                            warn!("Request failed after {retry} retries: {e:#}");
+                            return;
+                        }
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        Ok(())
+    }
+}
+
+async fn attempt<R, T, F>(
+    client: &Client<TdJson>,
+    request: R,
+    send: fn(Client<TdJson>, R) -> F,
+) -> Result<()>
+where
+    F: Future<Output = Result<T, rust_tdlib::errors::Error>>,
+{
+    send(client.clone(), request)
+        .await
+        .map(drop)
+        .map_err(|e| Report::from(Error::from(e)))
+}
+
+/// How long to wait before sending a request that failed with `err` again,
+/// `backoff` after transient errors. `None` if it isn't worth another try.
+fn retry_wait(err: &Report, backoff: Duration) -> Option<Duration> {
+    match flood_wait(err) {
+        Some(secs) if secs <= MAX_RETRY_WAIT_SECS => Some(Duration::from_secs(secs)),
+        Some(_) => None,
+        None if Error::find(err).map_or(false, Error::is_transient) => Some(backoff),
+        None => None,
+    }
+}
+
+/// File next to TDLib's database naming the bot the session belongs to
+const SESSION_OWNER: &str = "bot_id";
+
+/// Numeric id of the bot, the part of the token before the colon
+fn token_bot_id(config: &Config) -> Option<i64> {
+    config.bot_token.split_once(':')?.0.parse().ok()
+}
+
+/// Make sure the TDLib session in `tdlib_dir` belongs to the configured bot
+/// before using it. TDLib ignores the token once it has a session, so a data
+/// dir copied from another bot would otherwise silently log in as that one.
+/// With `reset_mismatched_session`, a session of another bot is deleted.
+pub async fn check_session(config: &Config) -> Result<()> {
+    let dir = config.tdlib_dir();
+    let Ok(owner) = tokio::fs::read_to_string(dir.join(SESSION_OWNER)).await else {
+        return Ok(());
+    };
+    let (Ok(owner), Some(expected)) = (owner.trim().parse::<i64>(), token_bot_id(config)) else {
+        return Ok(());
+    };
+    if owner == expected {
+        return Ok(());
+    }
+
+    if !config.reset_mismatched_session {
+        bail!(
+            "TDLib session in {} belongs to bot {owner}, but the token is for bot {expected}. \
+             Point `data_dir` elsewhere, delete the session or set `reset_mismatched_session`.",
+            dir.display()
+        );
+    }
+
+    warn!("Deleting TDLib session of bot {owner} to start a fresh one for bot {expected}");
+    tokio::fs::remove_dir_all(&dir)
+        .await
+        .wrap_err_with(|| format!("Failed to delete {}", dir.display()))
+}
+
+/// Check who TDLib logged in as against the token, and remember the owner of
+/// the session for [`check_session`]
+pub async fn record_session(config: &Config, bot_id: i64) -> Result<()> {
+    tokio::fs::write(config.tdlib_dir().join(SESSION_OWNER), bot_id.to_string())
+        .await
+        .wrap_err("Failed to record session owner")?;
+
+    match token_bot_id(config) {
+        Some(expected) if expected != bot_id && config.reset_mismatched_session => bail!(
+            "Logged in as bot {bot_id} from an existing session instead of bot {expected}. \
+             Restart to start a fresh session."
+        ),
+        Some(expected) if expected != bot_id => bail!(
+            "Logged in as bot {bot_id} from an existing session instead of bot {expected}. \
+             Point `data_dir` elsewhere, delete {} or set `reset_mismatched_session` and \
+             restart.",
+            config.tdlib_dir().display()
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub async fn init(config: &Config) -> Result<(Client<TdJson>, WorkerHandle)> {
+    let tdlib_dir = config.tdlib_dir();
     let tdlib_params = TdlibParameters::builder()
-        .database_directory(
-            config
-                .tdlib_dir()
-                .as_os_str()
-                .to_str()
-                .expect("Non-utf8 path"),
-        )
+        .database_directory(error::utf8_path(&tdlib_dir)?)
         .system_language_code("en")
         .device_model("Desktop")
         .system_version("0.0")
@@ -55,8 +324,34 @@ pub async fn init(config: &Config) -> Result<(Client<TdJson>, WorkerHandle)> {
         .api_hash(config.api_hash.clone())
         .build();
 
+    set_log_verbosity_level(1);
+
+    // Proxies have to be in place before TDLib first tries to connect
+    let proxy = config.proxy()?;
+    if let Some(proxy) = &proxy {
+        info!("Using {proxy}");
+    }
+    let params = tdlib_params.clone();
+    tokio::task::spawn_blocking(move || configure_proxy(proxy.as_ref(), params))
+        .await
+        .wrap_err("Proxy setup panicked")?
+        .wrap_err("Failed to set up proxy")?;
+
+    // Only started now, since the worker receives from TDLib as well and
+    // `td_receive` can't be called from two threads at once
+    let mut worker = Worker::builder()
+        .with_auth_state_handler(BotTokenHandler {
+            bot_token: config.bot_token.clone(),
+        })
+        .build()?;
+    let background = worker.start();
+
     // The buffer should be big enough for all initial updates to arrive
-    let (send, recv) = tokio::sync::mpsc::channel(1024);
+    let (send, recv) = tokio::sync::mpsc::channel(config.update_buffer);
+    info!(
+        "Update buffer: {}, catch up: {}",
+        config.update_buffer, config.catch_up
+    );
 
     let client = rust_tdlib::client::Client::builder()
         .with_client_auth_state_handler(BotTokenHandler {
@@ -67,8 +362,6 @@ pub async fn init(config: &Config) -> Result<(Client<TdJson>, WorkerHandle)> {
         .with_tdlib_parameters(tdlib_params)
         .build()?;
 
-    set_log_verbosity_level(1);
-
     info!("TDLib logging in");
 
     let client = worker.bind_client(client).await?;
@@ -96,6 +389,211 @@ pub async fn init(config: &Config) -> Result<(Client<TdJson>, WorkerHandle)> {
     ))
 }
 
+/// Replace the proxies in TDLib's database with the configured one, if any.
+/// TDLib connects as soon as it has its parameters and reads proxies from its
+/// database then, so a bot that can only reach Telegram through the proxy
+/// would never get to log in and add it. A short-lived client sets them up
+/// instead, before the worker opens the database. Proxies left from earlier
+/// runs are removed rather than added to, so restarts don't pile them up.
+fn configure_proxy(proxy: Option<&Proxy>, params: TdlibParameters) -> Result<()> {
+    let mut raw = RawClient::new();
+
+    raw.request(&SetTdlibParameters::builder().parameters(params).build())?;
+    raw.request(&CheckDatabaseEncryptionKey::builder().build())?;
+
+    let proxies = raw.request(&GetProxies::builder().build())?;
+    for id in proxy_ids(&proxies) {
+        raw.request(&RemoveProxy::builder().proxy_id(id).build())?;
+    }
+    if let Some(proxy) = proxy {
+        raw.request(&proxy.add_request())?;
+    }
+
+    raw.close()
+}
+
+/// Ids of the proxies in a `proxies` answer
+fn proxy_ids(proxies: &Value) -> Vec<i32> {
+    proxies["proxies"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|proxy| proxy["id"].as_i64())
+        .map(|id| id as i32)
+        .collect()
+}
+
+/// How long the proxy setup waits for TDLib to answer a request
+const RAW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// TDLib client spoken to in JSON directly, for the few requests that have to
+/// be sent before the worker takes over. Blocks, so only use it off the
+/// runtime.
+struct RawClient {
+    id: i32,
+    requests: u64,
+}
+
+impl RawClient {
+    fn new() -> Self {
+        RawClient {
+            id: tdjson::new_client(),
+            requests: 0,
+        }
+    }
+
+    /// Send a request and wait for TDLib to answer it
+    fn request(&mut self, request: &impl Serialize) -> Result<Value> {
+        self.requests += 1;
+        let extra = format!("setup-{}", self.requests);
+
+        let mut request = serde_json::to_value(request)?;
+        let name = request["@type"].as_str().unwrap_or_default().to_owned();
+        request["@extra"] = Value::from(extra.as_str());
+        tdjson::send(self.id, &request.to_string());
+
+        self.wait(|msg| answer(msg, &extra))
+            .wrap_err_with(|| format!("`{name}` failed"))?
+    }
+
+    /// Close the client and wait until it let go of the database
+    fn close(mut self) -> Result<()> {
+        self.request(&Close::builder().build())?;
+
+        self.wait(|msg| {
+            let closed = msg["@type"] == "updateAuthorizationState"
+                && msg["authorization_state"]["@type"] == "authorizationStateClosed";
+            closed.then_some(())
+        })
+    }
+
+    /// Receive messages for this client until `f` picks one
+    fn wait<T>(&self, mut f: impl FnMut(&Value) -> Option<T>) -> Result<T> {
+        let deadline = Instant::now() + RAW_TIMEOUT;
+
+        while Instant::now() < deadline {
+            let Some(msg) = tdjson::receive(1.0) else {
+                continue;
+            };
+            let msg: Value = serde_json::from_str(&msg)?;
+            if msg["@client_id"] != self.id {
+                continue;
+            }
+            if let Some(res) = f(&msg) {
+                return Ok(res);
+            }
+        }
+
+        bail!("TDLib didn't answer within {RAW_TIMEOUT:?}")
+    }
+}
+
+/// Answer to the request tagged with `extra`, if `msg` is it
+fn answer(msg: &Value, extra: &str) -> Option<Result<Value>> {
+    if msg["@extra"] != extra {
+        return None;
+    }
+
+    if msg["@type"] == "error" {
+        return Error::Telegram {
+            code: msg["code"].as_i64().unwrap_or_default() as i32,
+            message: msg["message"].as_str().unwrap_or_default().to_owned(),
+        }
+        .pipe(|e| Err(e.into()))
+        .pipe(Some);
+    }
+
+    Some(Ok(msg.clone()))
+}
+
+/// Proxy to connect to Telegram through, parsed from
+/// `<socks5|http>://[user:password@]host:port`
+#[derive(Clone)]
+pub struct Proxy {
+    kind: ProxyKind,
+    server: String,
+    port: i32,
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+impl Proxy {
+    fn add_request(&self) -> AddProxy {
+        let type_ = match self.kind {
+            ProxyKind::Socks5 => ProxyTypeSocks5::builder()
+                .username(&self.username)
+                .password(&self.password)
+                .build()
+                .pipe(ProxyType::Socks5),
+            ProxyKind::Http => ProxyTypeHttp::builder()
+                .username(&self.username)
+                .password(&self.password)
+                .build()
+                .pipe(ProxyType::Http),
+        };
+
+        AddProxy::builder()
+            .server(&self.server)
+            .port(self.port)
+            .enable(true)
+            .type_(type_)
+            .build()
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = Report;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| eyre!("Missing scheme, expected socks5:// or http://"))?;
+        let kind = match scheme {
+            "socks5" => ProxyKind::Socks5,
+            "http" => ProxyKind::Http,
+            _ => bail!("Unsupported scheme `{scheme}`, expected socks5 or http"),
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => (auth.split_once(':').unwrap_or((auth, "")), addr),
+            None => (("", ""), rest),
+        };
+
+        let (server, port) = addr.rsplit_once(':').ok_or_else(|| eyre!("Missing port"))?;
+        if server.is_empty() {
+            bail!("Missing host");
+        }
+        let port = port.parse().map_err(|_| eyre!("Invalid port `{port}`"))?;
+
+        Ok(Self {
+            kind,
+            server: server.to_owned(),
+            port,
+            username: auth.0.to_owned(),
+            password: auth.1.to_owned(),
+        })
+    }
+}
+
+/// Leaves out credentials
+impl Display for Proxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ProxyKind::Socks5 => "SOCKS5",
+            ProxyKind::Http => "HTTP",
+        };
+
+        write!(f, "{kind} proxy at {}:{}", self.server, self.port)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BotTokenHandler {
     bot_token: String,
@@ -182,3 +680,160 @@ impl ClientAuthStateHandler for BotTokenHandler {
         (String::new(), String::new())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telegram(code: i32, message: &str) -> Report {
+        Report::from(Error::Telegram {
+            code,
+            message: message.to_owned(),
+        })
+        .wrap_err("Failed to do something")
+    }
+
+    #[test]
+    fn chat_invalid() {
+        assert!(is_chat_invalid(&telegram(400, "Chat not found")));
+        assert!(is_chat_invalid(&telegram(400, "PEER_ID_INVALID")));
+        assert!(!is_chat_invalid(&telegram(400, "QUERY_ID_INVALID")));
+        assert!(!is_chat_invalid(&telegram(500, "Chat not found")));
+        // Only errors from Telegram count, not ones mentioning it
+        assert!(!is_chat_invalid(&eyre!("PEER_ID_INVALID")));
+    }
+
+    #[test]
+    fn query_expired() {
+        let expired = telegram(400, "QUERY_ID_INVALID");
+        assert!(is_query_expired(&expired));
+        assert_eq!(flood_wait(&expired), None);
+
+        let too_old = telegram(
+            400,
+            "Bad Request: query is too old and response timeout expired or query ID is invalid",
+        );
+        assert!(is_query_expired(&too_old));
+
+        let flood = telegram(429, "Too Many Requests: retry after 3");
+        assert!(!is_query_expired(&flood));
+        assert_eq!(flood_wait(&flood), Some(3));
+
+        assert!(!is_query_expired(&eyre!("QUERY_ID_INVALID")));
+    }
+
+    #[test]
+    fn flood_waits() {
+        assert_eq!(
+            flood_wait(&telegram(429, "Too Many Requests: retry after 17")),
+            Some(17)
+        );
+        assert_eq!(flood_wait(&telegram(429, "Too Many Requests")), None);
+        assert_eq!(flood_wait(&telegram(400, "retry after 17")), None);
+        assert_eq!(
+            flood_wait(&eyre!("429 Too Many Requests: retry after 17")),
+            None
+        );
+    }
+
+    #[test]
+    fn raw_flood_waits() {
+        // As `Retrier` gets them, before they're converted to an `Error`
+        let raw = |code, message: &str| {
+            rust_tdlib::types::Error::builder()
+                .code(code)
+                .message(message)
+                .build()
+                .pipe(rust_tdlib::errors::Error::TDLibError)
+                .pipe(Report::from)
+        };
+
+        let flood = raw(429, "Too Many Requests: retry after 3");
+        assert_eq!(flood_wait(&flood), Some(3));
+        assert_eq!(
+            retry_wait(&flood, Duration::from_millis(100)),
+            Some(Duration::from_secs(3))
+        );
+        // Telegram's own name for it
+        assert_eq!(flood_wait(&raw(420, "FLOOD_WAIT_3")), Some(3));
+        assert_eq!(flood_wait(&raw(420, "FLOOD_WAIT")), None);
+        assert_eq!(flood_wait(&raw(400, "FLOOD_WAIT_3")), None);
+    }
+
+    #[test]
+    fn file_invalid() {
+        assert!(is_file_invalid(&telegram(400, "FILE_REFERENCE_EXPIRED")));
+        assert!(is_file_invalid(&telegram(
+            400,
+            "Wrong remote file identifier specified: can't unserialize it"
+        )));
+        assert!(!is_file_invalid(&telegram(400, "QUERY_ID_INVALID")));
+        assert!(!is_file_invalid(&eyre!("FILE_ID_INVALID")));
+    }
+
+    #[test]
+    fn raw_answers() {
+        let ok = serde_json::json!({ "@type": "ok", "@extra": "setup-1", "@client_id": 1 });
+        assert!(answer(&ok, "setup-1").unwrap().is_ok());
+        assert!(answer(&ok, "setup-2").is_none());
+
+        let error = serde_json::json!({
+            "@type": "error",
+            "@extra": "setup-2",
+            "code": 400,
+            "message": "Invalid proxy",
+        });
+        let e = answer(&error, "setup-2").unwrap().unwrap_err();
+        assert_eq!(telegram_error(&e), Some((400, "Invalid proxy")));
+
+        // Updates carry no `@extra`
+        let update = serde_json::json!({ "@type": "updateOption", "@client_id": 1 });
+        assert!(answer(&update, "setup-1").is_none());
+    }
+
+    #[test]
+    fn proxy_list() {
+        let proxies = serde_json::json!({
+            "@type": "proxies",
+            "proxies": [
+                { "@type": "proxy", "id": 1, "server": "a", "port": 1080 },
+                { "@type": "proxy", "id": 4, "server": "b", "port": 8080 },
+            ],
+        });
+        assert_eq!(proxy_ids(&proxies), [1, 4]);
+        assert!(proxy_ids(&serde_json::json!({ "@type": "proxies", "proxies": [] })).is_empty());
+    }
+
+    #[test]
+    fn retry_waits() {
+        let backoff = Duration::from_millis(100);
+        assert_eq!(
+            retry_wait(&telegram(429, "Too Many Requests: retry after 3"), backoff),
+            Some(Duration::from_secs(3))
+        );
+        // Nobody is waiting a minute for an answer
+        assert_eq!(
+            retry_wait(&telegram(429, "Too Many Requests: retry after 60"), backoff),
+            None
+        );
+        assert_eq!(
+            retry_wait(&telegram(500, "Internal Server Error"), backoff),
+            Some(backoff)
+        );
+        assert_eq!(
+            retry_wait(&telegram(400, "MESSAGE_TOO_LONG"), backoff),
+            None
+        );
+    }
+
+    #[test]
+    fn server_message_ids() {
+        assert_eq!(server_message_id(1 << 20), 1);
+        assert_eq!(server_message_id(42 << 20), 42);
+        // Ids past the 32-bit range survive
+        let server_id = i64::from(i32::MAX) + 5;
+        assert_eq!(server_message_id(server_id << 20), server_id);
+        // Low bits TDLib uses for local and scheduled messages are dropped
+        assert_eq!(server_message_id((7 << 20) | 1), 7);
+    }
+}
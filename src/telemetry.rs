@@ -0,0 +1,57 @@
+//! Exporting tracing spans over OTLP. Logs keep going through `log`, only
+//! spans are exported, and only with the `otlp` feature enabled.
+
+use color_eyre::Result;
+
+use crate::Config;
+
+#[cfg(feature = "otlp")]
+pub fn init(config: &Config) -> Result<()> {
+    use opentelemetry::{
+        runtime::TokioCurrentThread,
+        sdk::{trace, Resource},
+        KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(());
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", "realmkbot")])),
+        )
+        .install_batch(TokioCurrentThread)?;
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+
+    info!("Exporting traces to {endpoint}");
+
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn init(config: &Config) -> Result<()> {
+    if config.otlp_endpoint.is_some() {
+        warn!("`otlp_endpoint` is set but the `otlp` feature is disabled, not exporting traces");
+    }
+
+    Ok(())
+}
+
+/// Flush spans that haven't been exported yet
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}